@@ -0,0 +1,170 @@
+// Band-limited resampler in the style of blip_buf: the APU's output only
+// ever changes in steps, so instead of filtering 1.79M samples a second,
+// every step is added to the output as a windowed sinc step placed at its
+// exact fractional sample position. Integrating the buffer afterwards turns
+// those band-limited impulses back into steps, without the aliasing plain
+// decimation would cause.
+// http://www.slack.net/~ant/bl-synth/
+
+const TAPS: usize = 16;
+const PHASES: usize = 64;
+// fraction of the output Nyquist frequency let through
+const CUTOFF: f64 = 0.9;
+
+pub struct Blip {
+    clock_rate: f64,
+    sample_rate: f64,
+    // output samples per input clock, including the rate adjustment
+    factor: f64,
+    // sample position of clock 0 of the current frame, relative to buffer[0]
+    offset: f64,
+    // clocks since the last read
+    time: u64,
+    amplitude: f32,
+    integrator: f32,
+    buffer: Vec<f32>,
+    // [phase][tap], every phase sums to 1
+    kernel: Vec<[f32; TAPS]>,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+    }
+}
+
+// Blackman window over -TAPS/2..TAPS/2
+fn window(x: f64) -> f64 {
+    let n = (x / TAPS as f64 + 0.5) * 2.0 * std::f64::consts::PI;
+    (0.42 - 0.5 * n.cos() + 0.08 * (2.0 * n).cos()).max(0.0)
+}
+
+impl Blip {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        let kernel = (0..=PHASES)
+            .map(|phase| {
+                let frac = phase as f64 / PHASES as f64;
+                let mut taps = [0.0f64; TAPS];
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    let x = k as f64 - (TAPS / 2 - 1) as f64 - frac;
+                    *tap = CUTOFF * sinc(CUTOFF * x) * window(x);
+                }
+                let sum: f64 = taps.iter().sum();
+                let mut normalized = [0.0f32; TAPS];
+                for (out, tap) in normalized.iter_mut().zip(taps.iter()) {
+                    *out = (tap / sum) as f32;
+                }
+                normalized
+            })
+            .collect();
+
+        Blip {
+            clock_rate,
+            sample_rate,
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            time: 0,
+            amplitude: 0.0,
+            integrator: 0.0,
+            buffer: vec![0.0; TAPS],
+            kernel,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.clock_rate = clock_rate;
+        self.set_rate_adjust(1.0);
+    }
+
+    // Stretches the output by `ratio`; above 1.0 more samples come out per
+    // emulated second. Takes effect from the next read on.
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.factor = self.sample_rate / self.clock_rate * ratio;
+    }
+
+    // Advances one input clock with the signal at `amplitude`
+    pub fn clock(&mut self, amplitude: f32) {
+        if amplitude != self.amplitude {
+            self.add_delta(amplitude - self.amplitude);
+            self.amplitude = amplitude;
+        }
+        self.time += 1;
+    }
+
+    fn add_delta(&mut self, delta: f32) {
+        let pos = self.offset + self.time as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * PHASES as f64).round() as usize;
+        if self.buffer.len() < index + TAPS {
+            self.buffer.resize(index + TAPS, 0.0);
+        }
+        for (sample, tap) in self.buffer[index..index + TAPS].iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * tap;
+        }
+    }
+
+    // Complete output samples waiting to be read
+    pub fn samples_available(&self) -> usize {
+        (self.offset + self.time as f64 * self.factor) as usize
+    }
+
+    // Moves all complete samples to `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let end = self.offset + self.time as f64 * self.factor;
+        let count = end as usize;
+        if self.buffer.len() < count + TAPS {
+            self.buffer.resize(count + TAPS, 0.0);
+        }
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset = end - count as f64;
+        self.time = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_count() {
+        let mut blip = Blip::new(1_789_773.0, 48000.0);
+        let mut out = Vec::new();
+        for _ in 0..1_789_773 {
+            blip.clock(0.0);
+        }
+        blip.read_samples(&mut out);
+        assert!((out.len() as i32 - 48000).abs() <= 1);
+
+        out.clear();
+        blip.set_rate_adjust(1.01);
+        for _ in 0..1_789_773 {
+            blip.clock(0.0);
+        }
+        blip.read_samples(&mut out);
+        assert!((out.len() as i32 - 48480).abs() <= 1);
+    }
+
+    #[test]
+    fn test_steps_settle() {
+        let mut blip = Blip::new(1_789_773.0, 44100.0);
+        let mut out = Vec::new();
+        for i in 0..20000 {
+            blip.clock(if i < 10000 { 0.5 } else { -0.25 });
+        }
+        blip.read_samples(&mut out);
+        // flat away from the edges, band-limited ringing around them
+        assert!((out[100] - 0.5).abs() < 0.001);
+        assert!((out[out.len() - 20] + 0.25).abs() < 0.001);
+        let edge = 10000 * 44100 / 1_789_773;
+        assert!(out[edge - 20..edge + 20].iter().all(|s| *s > -0.35 && *s < 0.6));
+    }
+}
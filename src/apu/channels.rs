@@ -0,0 +1,655 @@
+// The five 2A03 sound channels and the units they are built from.
+// https://www.nesdev.org/wiki/APU
+
+use crate::region::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16,
+    28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// in CPU cycles
+const NOISE_PERIODS_NTSC: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const NOISE_PERIODS_PAL: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+const DMC_RATES_NTSC: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const DMC_RATES_PAL: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+// https://www.nesdev.org/wiki/APU_Length_Counter
+#[derive(Default)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub halt: bool,
+    pub counter: u8,
+}
+
+impl LengthCounter {
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1f) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,
+    pub constant: bool,
+    // constant volume, or the divider period
+    pub period: u8,
+    divider: u8,
+    pub decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.period = value & 0x0f;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant);
+        w.write_u8(self.period);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.period = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/APU_Sweep
+#[derive(Default)]
+pub struct Sweep {
+    pub enabled: bool,
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    reload: bool,
+    divider: u8,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.enabled = value & 0x80 != 0;
+        self.period = (value >> 4) & 7;
+        self.negate = value & 0x08 != 0;
+        self.shift = value & 7;
+        self.reload = true;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.period);
+        w.write_bool(self.negate);
+        w.write_u8(self.shift);
+        w.write_bool(self.reload);
+        w.write_u8(self.divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.period = r.read_u8()?;
+        self.negate = r.read_bool()?;
+        self.shift = r.read_u8()?;
+        self.reload = r.read_bool()?;
+        self.divider = r.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/APU_Pulse
+pub struct Pulse {
+    // pulse 1 negates its sweep in ones' complement, pulse 2 in two's
+    ones_complement: bool,
+    pub duty: u8,
+    pub step: u8,
+    pub period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub sweep: Sweep,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    // $4000-$4003 or $4004-$4007, by register number
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => self.sweep.write(value),
+            2 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((value as u16 & 7) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Clocked every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        let target = self.sweep_target();
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.sweep_muted(target) {
+            self.period = target as u16;
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> i32 {
+        let change = (self.period >> self.sweep.shift) as i32;
+        if !self.sweep.negate {
+            self.period as i32 + change
+        } else if self.ones_complement {
+            self.period as i32 - change - 1
+        } else {
+            self.period as i32 - change
+        }
+    }
+
+    // The sweep unit silences the channel even while it's disabled
+    fn sweep_muted(&self, target: i32) -> bool {
+        self.period < 8 || target > 0x7ff
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep_muted(self.sweep_target()) || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        self.envelope.save_state(w);
+        self.sweep.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.duty = r.read_u8()?;
+        self.step = r.read_u8()?;
+        self.period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.sweep.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
+
+// https://www.nesdev.org/wiki/APU_Triangle
+#[derive(Default)]
+pub struct Triangle {
+    pub step: u8,
+    pub period: u16,
+    timer: u16,
+    // the control flag doubles as the length counter halt
+    pub control: bool,
+    pub linear_reload_value: u8,
+    pub linear_counter: u8,
+    linear_reload: bool,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    // $4008-$400B by register number, $4009 is unused
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = value & 0x7f;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((value as u16 & 7) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // Halting the sequencer keeps the last level on the output
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.step = r.read_u8()?;
+        self.period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.length.load_state(r)
+    }
+}
+
+// https://www.nesdev.org/wiki/APU_Noise
+pub struct Noise {
+    // short mode taps bit 6 instead of bit 1, for 93 step metallic loops
+    pub short_mode: bool,
+    pub period_index: u8,
+    timer: u16,
+    pub shift: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            short_mode: false,
+            period_index: 0,
+            timer: 0,
+            // the shift register powers up as 1
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    // $400C-$400F by register number, $400D is unused
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.period_index = value & 0x0f;
+            }
+            _ => {
+                self.length.load(value >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub fn period(&self, region: Region) -> u16 {
+        match region {
+            Region::Pal => NOISE_PERIODS_PAL[self.period_index as usize],
+            _ => NOISE_PERIODS_NTSC[self.period_index as usize],
+        }
+    }
+
+    // Clocked every CPU cycle, the period table is in CPU cycles
+    pub fn clock_timer(&mut self, region: Region) {
+        if self.timer == 0 {
+            self.timer = self.period(region) - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.short_mode);
+        w.write_u8(self.period_index);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.short_mode = r.read_bool()?;
+        self.period_index = r.read_u8()?;
+        self.timer = r.read_u16()?;
+        self.shift = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
+
+// Delta modulation channel: plays 1 bit delta encoded samples that it
+// fetches from CPU memory itself, stalling the CPU for every byte.
+// https://www.nesdev.org/wiki/APU_DMC
+#[derive(Default)]
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub looping: bool,
+    pub rate_index: u8,
+    timer: u16,
+    pub level: u8,
+
+    pub sample_address: u16,
+    pub sample_length: u16,
+    pub current_address: u16,
+    pub bytes_remaining: u16,
+    pub sample_buffer: Option<u8>,
+    // a fetch was handed to the bus and hasn't come back yet
+    fetch_pending: bool,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+impl Dmc {
+    // $4010-$4013 by register number
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.rate_index = value & 0x0f;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = value & 0x7f,
+            2 => self.sample_address = 0xc000 | (value as u16) << 6,
+            _ => self.sample_length = (value as u16) << 4 | 1,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn rate(&self, region: Region) -> u16 {
+        match region {
+            Region::Pal => DMC_RATES_PAL[self.rate_index as usize],
+            _ => DMC_RATES_NTSC[self.rate_index as usize],
+        }
+    }
+
+    // Clocked every CPU cycle, the rate table is in CPU cycles
+    pub fn clock_timer(&mut self, region: Region) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate(region) - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining = self.bits_remaining.saturating_sub(1);
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Address of the next sample byte, once the buffer has run empty
+    pub fn take_fetch_request(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && !self.fetch_pending {
+            self.fetch_pending = true;
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fetch_pending(&self) -> bool {
+        self.fetch_pending
+    }
+
+    pub fn fill_sample_buffer(&mut self, byte: u8) {
+        self.fetch_pending = false;
+        if self.bytes_remaining == 0 {
+            // disabled while the fetch was in flight
+            return;
+        }
+        self.sample_buffer = Some(byte);
+        self.current_address = if self.current_address == 0xffff { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.looping);
+        w.write_u8(self.rate_index);
+        w.write_u16(self.timer);
+        w.write_u8(self.level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_bool(self.fetch_pending);
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+        w.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.rate_index = r.read_u8()?;
+        self.timer = r.read_u16()?;
+        self.level = r.read_u8()?;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let buffered = r.read_bool()?;
+        let byte = r.read_u8()?;
+        self.sample_buffer = if buffered { Some(byte) } else { None };
+        self.fetch_pending = r.read_bool()?;
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+// The APU's DACs aren't linear: the pulse channels share one output pin
+// and triangle, noise and DMC another, and each pin's level flattens out
+// the more channels drive it. These are the formulas the usual lookup
+// tables are generated from, evaluated directly so channel volumes can
+// scale the levels going in.
+// https://www.nesdev.org/wiki/APU_Mixer
+//
+// The console's output stage then runs the mix through two high-pass and
+// one low-pass RC filter, done at the output sample rate by OutputFilter.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    // cartridge sound chips, mixed in linearly
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Channel, String> {
+        Channel::ALL
+            .iter()
+            .find(|channel| channel.name() == name.to_ascii_lowercase())
+            .copied()
+            .ok_or_else(|| format!("unknown channel '{}', expected pulse1, pulse2, triangle, noise, dmc or expansion", name))
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub struct Mixer {
+    volumes: [f32; 6],
+    muted: [bool; 6],
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer {
+            volumes: [1.0; 6],
+            muted: [false; 6],
+        }
+    }
+}
+
+impl Mixer {
+    pub fn volume(&self, channel: Channel) -> f32 {
+        self.volumes[channel as usize]
+    }
+
+    // 0.0 is silent, 1.0 the console's own level
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.volumes[channel as usize] = volume.max(0.0);
+    }
+
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    // Mutes everything but `channel`
+    pub fn solo(&mut self, channel: Channel) {
+        for other in Channel::ALL.iter() {
+            self.set_muted(*other, *other != channel);
+        }
+    }
+
+    fn gain(&self, channel: Channel) -> f32 {
+        if self.muted(channel) {
+            0.0
+        } else {
+            self.volume(channel)
+        }
+    }
+
+    // Mixes pulse 1, pulse 2, triangle, noise and DMC levels as returned
+    // by Apu::channel_outputs, plus expansion audio, to 0.0 to about 1.0
+    pub fn mix(&self, levels: [u8; 5], expansion: f32) -> f32 {
+        let level = |channel: Channel| levels[channel as usize] as f32 * self.gain(channel);
+
+        let pulse = level(Channel::Pulse1) + level(Channel::Pulse2);
+        let pulse_out = if pulse > 0.0 { 95.88 / (8128.0 / pulse + 100.0) } else { 0.0 };
+        let tnd = level(Channel::Triangle) / 8227.0 + level(Channel::Noise) / 12241.0 + level(Channel::Dmc) / 22638.0;
+        let tnd_out = if tnd > 0.0 { 159.79 / (1.0 / tnd + 100.0) } else { 0.0 };
+
+        pulse_out + tnd_out + expansion * self.gain(Channel::Expansion)
+    }
+
+    // What `channel` contributes on its own, for isolated recordings. Mute
+    // and volume settings don't apply.
+    pub fn mix_channel(&self, channel: Channel, levels: [u8; 5], expansion: f32) -> f32 {
+        let level = match channel {
+            Channel::Expansion => return expansion,
+            _ => levels[channel as usize] as f32,
+        };
+        match channel {
+            Channel::Pulse1 | Channel::Pulse2 if level > 0.0 => 95.88 / (8128.0 / level + 100.0),
+            Channel::Triangle if level > 0.0 => 159.79 / (8227.0 / level + 100.0),
+            Channel::Noise if level > 0.0 => 159.79 / (12241.0 / level + 100.0),
+            Channel::Dmc if level > 0.0 => 159.79 / (22638.0 / level + 100.0),
+            _ => 0.0,
+        }
+    }
+}
+
+// First order RC high- and low-pass filters
+// https://en.wikipedia.org/wiki/High-pass_filter#Discrete-time_realization
+enum Stage {
+    HighPass { alpha: f32, last_in: f32, last_out: f32 },
+    LowPass { alpha: f32, last_out: f32 },
+}
+
+impl Stage {
+    fn high_pass(cutoff: f32, sample_rate: f32) -> Stage {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Stage::HighPass { alpha: rc / (rc + dt), last_in: 0.0, last_out: 0.0 }
+    }
+
+    fn low_pass(cutoff: f32, sample_rate: f32) -> Stage {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Stage::LowPass { alpha: dt / (rc + dt), last_out: 0.0 }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        match self {
+            Stage::HighPass { alpha, last_in, last_out } => {
+                *last_out = *alpha * (*last_out + sample - *last_in);
+                *last_in = sample;
+                *last_out
+            }
+            Stage::LowPass { alpha, last_out } => {
+                *last_out += *alpha * (sample - *last_out);
+                *last_out
+            }
+        }
+    }
+}
+
+// The NES's output stage: high-pass at 90Hz and 440Hz, low-pass at 14kHz.
+// Also takes the DC offset out of the mix.
+pub struct OutputFilter {
+    stages: [Stage; 3],
+}
+
+impl OutputFilter {
+    pub fn new(sample_rate: f32) -> Self {
+        OutputFilter {
+            stages: [
+                Stage::high_pass(90.0, sample_rate),
+                Stage::high_pass(440.0, sample_rate),
+                Stage::low_pass(14000.0, sample_rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            for stage in self.stages.iter_mut() {
+                *sample = stage.process(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nonlinear_mix() {
+        let mixer = Mixer::default();
+        assert_eq!(mixer.mix([0; 5], 0.0), 0.0);
+        // the usual table values
+        assert!((mixer.mix([15, 15, 0, 0, 0], 0.0) - 0.2583).abs() < 0.0005);
+        assert!((mixer.mix([0, 0, 15, 15, 127], 0.0) - 0.7416).abs() < 0.0005);
+        // two channels together are quieter than the sum of both alone
+        let one = mixer.mix([15, 0, 0, 0, 0], 0.0);
+        assert!(mixer.mix([15, 15, 0, 0, 0], 0.0) < 2.0 * one);
+    }
+
+    #[test]
+    fn test_volume_and_mute() {
+        let mut mixer = Mixer::default();
+        mixer.set_muted(Channel::Pulse1, true);
+        assert_eq!(mixer.mix([15, 0, 0, 0, 0], 0.0), 0.0);
+        mixer.solo(Channel::Noise);
+        assert_eq!(mixer.mix([15, 15, 15, 0, 127], 0.5), 0.0);
+        assert!(mixer.mix([0, 0, 0, 15, 0], 0.0) > 0.0);
+
+        let mut mixer = Mixer::default();
+        let full = mixer.mix([0, 0, 15, 0, 0], 0.0);
+        mixer.set_volume(Channel::Triangle, 0.5);
+        let half = mixer.mix([0, 0, 15, 0, 0], 0.0);
+        assert!(half < full && half > 0.4 * full);
+
+        // stems ignore the mute settings
+        assert_eq!(mixer.mix_channel(Channel::Triangle, [0, 0, 15, 0, 0], 0.0), full);
+
+        assert_eq!(Channel::parse("DMC"), Ok(Channel::Dmc));
+        assert!(Channel::parse("square").is_err());
+    }
+
+    #[test]
+    fn test_output_filter_removes_dc() {
+        let mut filter = OutputFilter::new(48000.0);
+        let mut samples = vec![0.5; 48000];
+        filter.process(&mut samples);
+        assert!(samples[0] > 0.1);
+        assert!(samples[47999].abs() < 0.001);
+    }
+}
@@ -0,0 +1,417 @@
+// The 2A03's audio processing unit: two pulse channels, a triangle, noise
+// and the DMC, sequenced by the frame counter. The bus clocks it once per
+// CPU cycle and serves the DMC's sample fetches.
+// https://www.nesdev.org/wiki/APU
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub mod blip;
+pub mod channels;
+pub mod mixer;
+pub mod wav;
+
+use blip::Blip;
+use channels::{Dmc, Noise, Pulse, Triangle};
+use mixer::{Channel, Mixer};
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub mixer: Mixer,
+    // level of a cartridge sound chip, 0.0 to 1.0; nothing drives it yet
+    pub expansion: f32,
+
+    region: Region,
+    cycles: u64,
+
+    // https://www.nesdev.org/wiki/APU_Frame_Counter
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // a $4017 write takes effect 3 or 4 cycles later
+    frame_reset_delay: u8,
+
+    // resamplers the frontend and recorders read the audio from, fed the
+    // full mix or a single channel
+    blips: Vec<(Option<Channel>, Rc<RefCell<Blip>>)>,
+    // off while the debugger replays history, which isn't new audio
+    output_enabled: bool,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            mixer: Mixer::default(),
+            expansion: 0.0,
+            region,
+            cycles: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+            blips: Vec::new(),
+            output_enabled: true,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        for (_, blip) in self.blips.iter() {
+            blip.borrow_mut().set_clock_rate(region.cpu_clock_hz() as f64);
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Feeds the mixed output into `blip`, one input clock per CPU cycle
+    pub fn attach_blip(&mut self, blip: Rc<RefCell<Blip>>) {
+        self.attach_channel_blip(None, blip);
+    }
+
+    // Same for the output of `channel` alone, or the full mix for None
+    pub fn attach_channel_blip(&mut self, channel: Option<Channel>, blip: Rc<RefCell<Blip>>) {
+        blip.borrow_mut().set_clock_rate(self.region.cpu_clock_hz() as f64);
+        self.blips.push((channel, blip));
+    }
+
+    pub fn detach_blip(&mut self, blip: &Rc<RefCell<Blip>>) {
+        self.blips.retain(|(_, attached)| !Rc::ptr_eq(attached, blip));
+    }
+
+    pub fn set_output_enabled(&mut self, enabled: bool) {
+        self.output_enabled = enabled;
+    }
+
+    // Runs one CPU cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer(self.region);
+        self.dmc.clock_timer(self.region);
+        if self.cycles.is_multiple_of(2) {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        if self.output_enabled && !self.blips.is_empty() {
+            let levels = self.channel_outputs();
+            let mix = self.mixer.mix(levels, self.expansion);
+            for (channel, blip) in self.blips.iter() {
+                let amplitude = match channel {
+                    Some(channel) => self.mixer.mix_channel(*channel, levels, self.expansion),
+                    None => mix,
+                };
+                blip.borrow_mut().clock(amplitude);
+            }
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+        }
+
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps(self.five_step);
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        } else if self.five_step {
+            if cycle == steps[4] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            } else if cycle == steps[5] {
+                self.frame_cycle = 0;
+            }
+        } else if cycle == steps[3] {
+            self.set_frame_irq();
+        } else if cycle == steps[4] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            self.set_frame_irq();
+        } else if cycle == steps[4] + 1 {
+            self.set_frame_irq();
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    // $4000-$4013 and $4017; $4014 and $4016 belong to the bus
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr & 3, value),
+            0x4004..=0x4007 => self.pulse2.write(addr & 3, value),
+            0x4008..=0x400b => self.triangle.write(addr & 3, value),
+            0x400c..=0x400f => self.noise.write(addr & 3, value),
+            0x4010..=0x4013 => self.dmc.write(addr & 3, value),
+            0x4015 => self.write_status(value),
+            0x4017 => self.write_frame_counter(value),
+            _ => {}
+        }
+    }
+
+    fn write_status(&mut self, value: u8) {
+        self.pulse1.length.set_enabled(value & 0x01 != 0);
+        self.pulse2.length.set_enabled(value & 0x02 != 0);
+        self.triangle.length.set_enabled(value & 0x04 != 0);
+        self.noise.length.set_enabled(value & 0x08 != 0);
+        self.dmc.set_enabled(value & 0x10 != 0);
+    }
+
+    fn write_frame_counter(&mut self, value: u8) {
+        self.five_step = value & 0x80 != 0;
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        self.frame_reset_delay = if self.cycles.is_multiple_of(2) { 3 } else { 4 };
+    }
+
+    // $4015: length counter and DMC status, and the pending IRQs. Reading
+    // acknowledges the frame IRQ.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse1.length.active() as u8)
+            | (self.pulse2.length.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Levels of pulse 1, pulse 2, triangle, noise (0-15) and DMC (0-127)
+    pub fn channel_outputs(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+
+    // The mixed output, 0.0 to about 1.0
+    pub fn output(&self) -> f32 {
+        self.mixer.mix(self.channel_outputs(), self.expansion)
+    }
+}
+
+impl Savestate for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_u64(self.cycles);
+        w.write_bool(self.five_step);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u32(self.frame_cycle);
+        w.write_u8(self.frame_reset_delay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.cycles = r.read_u64()?;
+        self.five_step = r.read_bool()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycle = r.read_u32()?;
+        self.frame_reset_delay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        run(&mut apu, 29827);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert_eq!(apu.read_status() & 0x40, 0);
+
+        // inhibited, and never raised in 5-step mode
+        apu.write_register(0x4017, 0x40);
+        run(&mut apu, 40000);
+        assert!(!apu.irq());
+        apu.write_register(0x4017, 0x80);
+        run(&mut apu, 40000);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // loading is ignored while the channel is disabled
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 1, 0);
+
+        apu.write_register(0x4015, 0x0f);
+        apu.write_register(0x4000, 0x10);
+        apu.write_register(0x4003, 0x18); // index 3, 2 half frames
+        assert_eq!(apu.read_status() & 1, 1);
+        run(&mut apu, 14913);
+        assert_eq!(apu.pulse1.length.counter, 1);
+        run(&mut apu, 29829 - 14913);
+        assert_eq!(apu.read_status() & 1, 0);
+
+        // disabling clears it right away
+        apu.write_register(0x400f, 0x08);
+        assert_eq!(apu.read_status() & 8, 8);
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.read_status() & 8, 0);
+    }
+
+    #[test]
+    fn test_pulse_output_and_sweep() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 9
+        apu.write_register(0x4000, 0xb9);
+        apu.write_register(0x4002, 0x10);
+        apu.write_register(0x4003, 0x00);
+        let mut levels = Vec::new();
+        for _ in 0..16 {
+            run(&mut apu, 34);
+            levels.push(apu.pulse1.output());
+        }
+        assert!(levels.contains(&9) && levels.contains(&0));
+
+        // periods below 8 mute the channel
+        apu.write_register(0x4002, 0x07);
+        assert_eq!(apu.pulse1.output(), 0);
+
+        // pulse 1 negates in ones' complement, pulse 2 doesn't
+        apu.write_register(0x4002, 0x40);
+        apu.write_register(0x4001, 0x89);
+        apu.write_register(0x4006, 0x40);
+        apu.write_register(0x4005, 0x89);
+        run(&mut apu, 14913);
+        assert_eq!(apu.pulse1.period, 0x40 - 0x20 - 1);
+        assert_eq!(apu.pulse2.period, 0x40 - 0x20);
+    }
+
+    #[test]
+    fn test_noise_modes() {
+        let mut noise = Noise::default();
+        // the shift register moves every 4 cycles at the shortest period
+        let mut long = std::collections::HashSet::new();
+        for _ in 0..32767 * 4 {
+            noise.clock_timer(Region::Ntsc);
+            long.insert(noise.shift);
+        }
+        assert_eq!(long.len(), 32767);
+
+        noise.write(2, 0x80);
+        for _ in 0..1000 {
+            noise.clock_timer(Region::Ntsc);
+        }
+        let mut short = std::collections::HashSet::new();
+        for _ in 0..1000 {
+            noise.clock_timer(Region::Ntsc);
+            short.insert(noise.shift);
+        }
+        assert!(short.len() == 93 || short.len() == 31);
+    }
+
+    #[test]
+    fn test_dmc_fetch_and_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4010, 0x80);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+
+        assert_eq!(apu.dmc.take_fetch_request(), Some(0xc040));
+        assert_eq!(apu.dmc.take_fetch_request(), None);
+        apu.dmc.fill_sample_buffer(0xff);
+        assert_eq!(apu.read_status() & 0x90, 0x80);
+        assert!(apu.irq());
+
+        // writing $4015 acknowledges it
+        apu.write_register(0x4015, 0x00);
+        assert!(!apu.irq());
+
+        // the output counter follows the sample bits
+        apu.write_register(0x4011, 0x40);
+        apu.write_register(0x4015, 0x10);
+        apu.dmc.take_fetch_request();
+        apu.dmc.fill_sample_buffer(0xff);
+        run(&mut apu, 428 * 10);
+        assert!(apu.dmc.output() > 0x40);
+    }
+}
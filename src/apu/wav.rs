@@ -0,0 +1,228 @@
+// Recording the audio to 16 bit mono WAV files, either just the mix or
+// with one extra file per channel ("stems"). The recorder resamples on its
+// own, independent of any sound device, so it works just as well when
+// running headless faster than real time.
+// http://soundfile.sapp.org/doc/WaveFormat/
+
+use super::blip::Blip;
+use super::mixer::{Channel, OutputFilter};
+use super::Apu;
+use crate::audio::to_pcm;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter {
+    path: String,
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+fn io_error(path: &str, e: std::io::Error) -> String {
+    format!("{}: {}", path, e)
+}
+
+pub fn header(sample_rate: u32, samples: u32) -> Vec<u8> {
+    let data_size = samples * 2;
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    // byte rate, block align, bits per sample
+    header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+impl WavWriter {
+    // The header is written with sizes of 0 and fixed up by `finish`
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| io_error(path, e))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&header(sample_rate, 0)).map_err(|e| io_error(path, e))?;
+        Ok(WavWriter {
+            path: path.to_string(),
+            file,
+            samples: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> Result<(), String> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes()).map_err(|e| io_error(&self.path, e))?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self, sample_rate: u32) -> Result<(), String> {
+        let path = self.path.clone();
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| io_error(&path, e))?;
+        self.file.write_all(&header(sample_rate, self.samples)).map_err(|e| io_error(&path, e))?;
+        self.file.flush().map_err(|e| io_error(&path, e))
+    }
+}
+
+struct Track {
+    blip: Rc<RefCell<Blip>>,
+    filter: OutputFilter,
+    writer: WavWriter,
+}
+
+pub struct WavRecorder {
+    sample_rate: u32,
+    tracks: Vec<Track>,
+    samples: Vec<f32>,
+    pcm: Vec<i16>,
+}
+
+// foo.wav -> foo-triangle.wav
+pub fn stem_path(path: &str, channel: Channel) -> String {
+    match path.strip_suffix(".wav") {
+        Some(base) => format!("{}-{}.wav", base, channel),
+        None => format!("{}-{}", path, channel),
+    }
+}
+
+impl WavRecorder {
+    // Starts recording the mix to `path`, and with `stems` every APU
+    // channel to a file of its own next to it
+    pub fn start(apu: &mut Apu, path: &str, stems: bool, sample_rate: u32) -> Result<Self, String> {
+        let mut files = vec![(None, path.to_string())];
+        if stems {
+            // expansion audio isn't part of the APU
+            for channel in Channel::ALL.iter().filter(|channel| **channel != Channel::Expansion) {
+                files.push((Some(*channel), stem_path(path, *channel)));
+            }
+        }
+
+        let mut tracks = Vec::new();
+        for (channel, path) in files {
+            let writer = WavWriter::create(&path, sample_rate)?;
+            let blip = Rc::new(RefCell::new(Blip::new(1.0, sample_rate as f64)));
+            apu.attach_channel_blip(channel, blip.clone());
+            tracks.push(Track {
+                blip,
+                filter: OutputFilter::new(sample_rate as f32),
+                writer,
+            });
+        }
+        Ok(WavRecorder {
+            sample_rate,
+            tracks,
+            samples: Vec::new(),
+            pcm: Vec::new(),
+        })
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        self.tracks.iter().map(|track| track.writer.path.as_str()).collect()
+    }
+
+    // Writes out what was recorded since the last call; call it every
+    // frame or so to keep the buffers small
+    pub fn update(&mut self) -> Result<(), String> {
+        for track in self.tracks.iter_mut() {
+            self.samples.clear();
+            track.blip.borrow_mut().read_samples(&mut self.samples);
+            track.filter.process(&mut self.samples);
+            self.pcm.clear();
+            to_pcm(&self.samples, 1.0, &mut self.pcm);
+            track.writer.write(&self.pcm)?;
+        }
+        Ok(())
+    }
+
+    // Detaches from the APU and finishes every file, even after errors;
+    // the first error is returned
+    pub fn stop(mut self, apu: &mut Apu) -> Result<(), String> {
+        for track in self.tracks.iter() {
+            apu.detach_blip(&track.blip);
+        }
+        let mut result = self.update();
+        for track in self.tracks {
+            let finished = track.writer.finish(self.sample_rate);
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::Region;
+
+    #[test]
+    fn test_header() {
+        let header = header(48000, 100);
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(&header[4..8], &(36u32 + 200).to_le_bytes());
+        assert_eq!(&header[24..28], &48000u32.to_le_bytes());
+        assert_eq!(&header[40..44], &200u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_record_stems() {
+        let dir = std::env::temp_dir().join(format!("wav_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.wav").to_string_lossy().to_string();
+
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0xbf);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0x00);
+
+        let mut recorder = WavRecorder::start(&mut apu, &path, true, 44100).unwrap();
+        assert_eq!(recorder.paths().len(), 6);
+        for _ in 0..1_789_773 / 10 {
+            apu.tick();
+        }
+        recorder.update().unwrap();
+        recorder.stop(&mut apu).unwrap();
+        assert!(apu.blips.is_empty());
+
+        let mix = std::fs::read(&path).unwrap();
+        let pulse = std::fs::read(stem_path(&path, Channel::Pulse1)).unwrap();
+        let noise = std::fs::read(stem_path(&path, Channel::Noise)).unwrap();
+        let samples = u32::from_le_bytes([mix[40], mix[41], mix[42], mix[43]]) / 2;
+        assert!((samples as i32 - 4410).abs() <= 1);
+        assert_eq!(mix.len(), 44 + samples as usize * 2);
+        assert_eq!(pulse.len(), mix.len());
+        assert!(pulse[44..].iter().any(|b| *b != 0));
+        assert!(noise[44..].iter().all(|b| *b == 0));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stop_after_write_error() {
+        // every write to /dev/full fails with "no space left"
+        if !std::path::Path::new("/dev/full").exists() {
+            return;
+        }
+        let mut apu = Apu::new(Region::Ntsc);
+        let mut recorder = WavRecorder::start(&mut apu, "/dev/full", false, 44100).unwrap();
+        for _ in 0..1_789_773 / 10 {
+            apu.tick();
+        }
+        assert!(recorder.update().is_err());
+        assert!(recorder.stop(&mut apu).is_err());
+        assert!(apu.blips.is_empty());
+    }
+}
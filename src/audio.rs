@@ -0,0 +1,129 @@
+// SDL audio output. The APU is resampled straight to the device rate by
+// apu::blip, filtered like the console's output stage and queued once per
+// frame.
+//
+// Frames are paced by the host clock, which never runs at exactly the rate
+// the sound card consumes samples at. Rather than letting the queue slowly
+// run dry (crackles) or pile up (growing latency), the resampling ratio is
+// nudged by up to half a percent depending on how full the queue is, which
+// keeps it hovering around the configured latency. The pitch change is far
+// too small to hear.
+// https://docs.libretro.com/development/cores/dynamic-rate-control/
+
+use crate::apu::blip::Blip;
+use crate::apu::mixer::OutputFilter;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const MAX_RATE_DELTA: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSettings {
+    pub sample_rate: u32,
+    // how much audio is kept queued
+    pub latency_ms: u32,
+    // 0.0 to 1.0
+    pub volume: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings { sample_rate: 48000, latency_ms: 60, volume: 0.8 }
+    }
+}
+
+pub struct AudioOutput {
+    queue: AudioQueue<i16>,
+    blip: Rc<RefCell<Blip>>,
+    // queued samples the rate control aims for
+    target: usize,
+    pub volume: f32,
+    filter: OutputFilter,
+    samples: Vec<f32>,
+    pcm: Vec<i16>,
+}
+
+// Resampling ratio for a queue holding `queued` of `target` samples
+pub fn rate_adjust(queued: usize, target: usize) -> f64 {
+    let fill = queued as f64 / target as f64;
+    1.0 + (MAX_RATE_DELTA * (1.0 - fill)).clamp(-MAX_RATE_DELTA, MAX_RATE_DELTA)
+}
+
+pub fn to_pcm(samples: &[f32], volume: f32, out: &mut Vec<i16>) {
+    out.extend(samples.iter().map(|s| (s * volume * 32767.0).round().clamp(-32768.0, 32767.0) as i16));
+}
+
+impl AudioOutput {
+    pub fn new(audio: &AudioSubsystem, settings: AudioSettings) -> Result<Self, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(settings.sample_rate as i32),
+            channels: Some(1),
+            samples: Some(512),
+        };
+        let queue = audio.open_queue::<i16, _>(None, &desired)?;
+        // the device may not support the rate asked for
+        let sample_rate = queue.spec().freq as f64;
+        queue.resume();
+
+        Ok(AudioOutput {
+            queue,
+            blip: Rc::new(RefCell::new(Blip::new(1.0, sample_rate))),
+            target: (sample_rate * settings.latency_ms as f64 / 1000.0) as usize,
+            volume: settings.volume,
+            filter: OutputFilter::new(sample_rate as f32),
+            samples: Vec::new(),
+            pcm: Vec::new(),
+        })
+    }
+
+    // To be attached to the APU
+    pub fn blip(&self) -> Rc<RefCell<Blip>> {
+        self.blip.clone()
+    }
+
+    fn queued_samples(&self) -> usize {
+        self.queue.size() as usize / 2
+    }
+
+    // Queues what the APU produced since the last call, once per frame
+    pub fn update(&mut self) {
+        let queued = self.queued_samples();
+        // after a stall (debugger, loading) drop what's queued instead of
+        // lagging behind for the rest of the session
+        if queued > self.target * 3 {
+            self.queue.clear();
+        }
+
+        let mut blip = self.blip.borrow_mut();
+        self.samples.clear();
+        blip.read_samples(&mut self.samples);
+        blip.set_rate_adjust(rate_adjust(self.queued_samples(), self.target));
+        self.filter.process(&mut self.samples);
+
+        self.pcm.clear();
+        to_pcm(&self.samples, self.volume, &mut self.pcm);
+        self.queue.queue(&self.pcm);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_adjust() {
+        assert_eq!(rate_adjust(100, 100), 1.0);
+        assert_eq!(rate_adjust(0, 100), 1.005);
+        assert!(rate_adjust(150, 100) < 1.0);
+        assert_eq!(rate_adjust(1000, 100), 0.995);
+    }
+
+    #[test]
+    fn test_to_pcm() {
+        let mut out = Vec::new();
+        to_pcm(&[0.0, 0.5, 2.0, -2.0], 0.5, &mut out);
+        assert_eq!(out, vec![0, 8192, 32767, -32767]);
+    }
+}
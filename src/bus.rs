@@ -8,7 +8,13 @@ use crate::cpu::Mem;
 use crate::cart::Rom;
 use crate::ppu::MyPPU;
 use crate::ppu::PPU;
+use crate::apu::Apu;
 use crate::controller::Joypad;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::cdl::CodeDataLogger;
+use crate::region::Region;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 const RAM_START: u16 = 0x0000;
 const RAM_MIRROR_END: u16 = 0x1FFF;
@@ -17,16 +23,50 @@ const PPU_REGISTERS_MIRROR_END: u16 = 0x3FFF;
 const CARTRIDGE_ROM_START: u16 = 0x8000;
 const CARTRIDGE_ROM_END: u16 = 0xFFFF;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A single bus transaction, recorded while an access log is enabled so
+// the debugger can match it against watchpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     prg_rom: Vec<u8>,
     ppu: MyPPU,
+    apu: Apu,
  
     cycles: usize,
+    // The CPU only ticks the bus once an instruction is done. PPU register
+    // accesses first run the PPU up to the cycle the access happens on; this
+    // is how many cycles of the current instruction it is ahead by.
+    instruction_cycles: u8,
+    ppu_ahead: u8,
+
+    region: Region,
+    // leftover fifths of a PPU dot, PAL runs 3.2 dots per CPU cycle
+    ppu_dot_fraction: u8,
+
+    // page written to $4014, copied once the current instruction is done
+    oam_dma_page: Option<u8>,
+    // address of a DMC sample fetch the APU asked for, and the fetched byte
+    dmc_dma: Option<u16>,
+    dmc_sample: Option<u8>,
+
     gameloop_callback: Box<dyn FnMut(&MyPPU, &mut Joypad) + 'call>,
+    gameloop_enabled: bool,
     joypad1: Joypad,
- 
+
+    access_log: Option<Vec<MemAccess>>,
+    cdl: Option<Rc<RefCell<CodeDataLogger>>>,
  }
  
  impl<'a> Bus<'a> {
@@ -34,15 +74,28 @@ pub struct Bus<'call> {
     where
         F: FnMut(&MyPPU, &mut Joypad) + 'call,
     {
-        let ppu = MyPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let region = rom.region.unwrap_or_default();
+        let mut ppu = MyPPU::new(rom.chr_rom, rom.screen_mirroring);
+        ppu.set_region(region);
  
         Bus {
             cpu_vram: [0; 2048],
             prg_rom: rom.prg_rom,
             ppu: ppu,
+            apu: Apu::new(region),
             cycles: 0,
+            instruction_cycles: 0,
+            ppu_ahead: 0,
+            region,
+            ppu_dot_fraction: 0,
+            oam_dma_page: None,
+            dmc_dma: None,
+            dmc_sample: None,
             gameloop_callback: Box::from(gameloop_callback),
+            gameloop_enabled: true,
             joypad1: Joypad::new(),
+            access_log: None,
+            cdl: None,
         }
     }
  
@@ -50,19 +103,221 @@ pub struct Bus<'call> {
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+        let skip = cycles.min(self.ppu_ahead);
+        self.ppu_ahead -= skip;
+        self.run_ppu(cycles - skip);
+        self.run_apu(cycles);
+    }
+
+    fn run_apu(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.apu.tick();
+        }
+        // the fetched byte goes to the DMC once the stall is over
+        if self.apu.dmc.fetch_pending() {
+            if let Some(sample) = self.dmc_sample.take() {
+                self.apu.dmc.fill_sample_buffer(sample);
+            }
+        }
+        if self.dmc_dma.is_none() {
+            self.dmc_dma = self.apu.dmc.take_fetch_request();
+        }
+    }
+
+    fn run_ppu(&mut self, cycles: u8) {
+        let mut frame_complete = false;
+        let dots_x5 = self.region.ppu_dots_per_cpu_cycle_x5();
+        for _ in 0..cycles {
+            self.ppu_dot_fraction += dots_x5;
+            frame_complete |= self.ppu.tick(self.ppu_dot_fraction / 5);
+            self.ppu_dot_fraction %= 5;
+        }
 
-        if !nmi_before && nmi_after {
+        if frame_complete && self.gameloop_enabled {
             (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
         }
     }
 
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    // Runs the DMA transfers the last instruction started, with the CPU
+    // halted for their duration.
+    // https://www.nesdev.org/wiki/DMA
+    pub fn run_dma(&mut self) {
+        // DMA reads aren't part of any instruction
+        self.instruction_cycles = 0;
+
+        if let Some(page) = self.oam_dma_page.take() {
+            // halt cycle, plus one more if that was a put cycle; reads can
+            // only happen on get (even) cycles
+            self.tick(1);
+            if self.cycles % 2 == 1 {
+                self.tick(1);
+            }
+            for i in 0..256u16 {
+                if self.dmc_dma.is_some() {
+                    // DMC takes this get cycle, OAM DMA realigns after it
+                    self.fetch_dmc_sample();
+                    self.tick(1);
+                }
+                let value = self.mem_read((page as u16) << 8 | i);
+                self.tick(1);
+                self.ppu.write_to_oam_data(value);
+                self.tick(1);
+            }
+        }
+
+        if self.dmc_dma.is_some() {
+            // halt, dummy and alignment cycles before the get
+            self.tick(2);
+            if self.cycles % 2 == 1 {
+                self.tick(1);
+            }
+            self.fetch_dmc_sample();
+        }
+    }
+
+    fn fetch_dmc_sample(&mut self) {
+        if let Some(addr) = self.dmc_dma.take() {
+            let value = self.mem_read(addr);
+            if let Some(cdl) = self.cdl.as_ref() {
+                cdl.borrow_mut().log_pcm(addr);
+            }
+            self.tick(1);
+            self.dmc_sample = Some(value);
+        }
+    }
+
+    // Stand-ins for the APU's side of a DMC fetch, which run_apu handles
+    // on its own, so the tests can look at the stall by itself
+    #[cfg(test)]
+    fn request_dmc_dma(&mut self, addr: u16) {
+        self.dmc_dma = Some(addr);
+    }
+
+    #[cfg(test)]
+    fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dmc_sample.take()
+    }
+
+    // Called by the CPU once it has decoded the opcode
+    pub fn set_instruction_cycles(&mut self, cycles: u8) {
+        self.instruction_cycles = cycles;
+    }
+
+    // Register reads and writes happen on the last cycle of an instruction
+    fn sync_ppu(&mut self) {
+        let target = self.instruction_cycles.saturating_sub(1);
+        if target > self.ppu_ahead {
+            let cycles = target - self.ppu_ahead;
+            self.ppu_ahead = target;
+            self.run_ppu(cycles);
+        }
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_interrupt()
     }
 
+    pub fn poll_irq_status(&mut self) -> bool {
+        // no mapper IRQs yet
+        self.apu.irq()
+    }
+
+    // The gameloop callback presents frames and polls the host for input.
+    // Replaying recorded history turns it off so neither happens.
+    pub fn set_gameloop_enabled(&mut self, enabled: bool) {
+        self.gameloop_enabled = enabled;
+    }
+
+    pub fn joypad1(&self) -> &Joypad {
+        &self.joypad1
+    }
+
+    pub fn joypad1_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad1
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // 16KB PRG bank currently mapped at `addr`, None outside of PRG ROM
+    pub fn prg_bank(&self, addr: u16) -> Option<u8> {
+        if addr < CARTRIDGE_ROM_START || self.prg_rom.is_empty() {
+            return None;
+        }
+        let offset = (addr - CARTRIDGE_ROM_START) as usize % self.prg_rom.len();
+        Some((offset / 0x4000) as u8)
+    }
+
+    pub fn ppu(&self) -> &MyPPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut MyPPU {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn set_access_log(&mut self, enabled: bool) {
+        self.access_log = if enabled { Some(Vec::new()) } else { None };
+        self.ppu.set_access_log(enabled);
+    }
+
+    pub fn take_access_log(&mut self) -> Vec<MemAccess> {
+        match self.access_log.as_mut() {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn attach_cdl(&mut self, cdl: Rc<RefCell<CodeDataLogger>>) {
+        self.ppu.cdl = Some(cdl.clone());
+        self.cdl = Some(cdl);
+    }
+
+    // Instruction fetch hook for the code/data logger
+    pub fn begin_instruction(&mut self, addr: u16) {
+        if let Some(cdl) = self.cdl.as_ref() {
+            cdl.borrow_mut().begin_instruction(addr);
+        }
+    }
+
+    fn log_access(&mut self, addr: u16, value: u8, kind: AccessKind) {
+        if let Some(log) = self.access_log.as_mut() {
+            log.push(MemAccess { addr, value, kind });
+        }
+    }
+
+    // Reads memory the way the CPU would see it, but without any of the
+    // side effects (clearing vblank, advancing the joypad shift register,
+    // bumping the PPU address). Used by debugging tools.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM_START..=RAM_MIRROR_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x2000..=PPU_REGISTERS_MIRROR_END => self.ppu.peek_register(addr & 0b00100000_00000111),
+            0x4015 => self.apu.peek_status(),
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
     fn read_prg_rom(&self, mut addr: u16) -> u8 {
         addr -= CARTRIDGE_ROM_START;
         //mirrors ROM for games with only 16KB PRG ROM
@@ -75,12 +330,41 @@ pub struct Bus<'call> {
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        if (0x2000..=PPU_REGISTERS_MIRROR_END).contains(&addr) {
+            self.sync_ppu();
+        }
+        let data = self.bus_read(addr);
+        self.log_access(addr, data, AccessKind::Read);
+        if let Some(cdl) = self.cdl.as_ref() {
+            cdl.borrow_mut().log_read(addr, data);
+        }
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.log_access(addr, data, AccessKind::Write);
+        if (0x2000..=PPU_REGISTERS_MIRROR_END).contains(&addr) {
+            self.sync_ppu();
+        }
+        if self.ppu.event_log_enabled() {
+            if let 0x2000..=PPU_REGISTERS_MIRROR_END | 0x4014 | CARTRIDGE_ROM_START..=CARTRIDGE_ROM_END = addr {
+                self.sync_ppu();
+                self.ppu.log_event(addr, data);
+            }
+        }
+        self.bus_write(addr, data);
+    }
+}
+
+impl Bus<'_> {
+    fn bus_read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM_START..=RAM_MIRROR_END => {
                 let mirrored_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirrored_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.read_open_bus(),
+            0x4014 => {
                 //panic!("Attempt to read from write-only PPU address {:x}", addr);
                 0
             }
@@ -91,14 +375,16 @@ impl Mem for Bus<'_> {
             
             0x2008..=PPU_REGISTERS_MIRROR_END => {
                 let mirrored_addr = addr & 0b00100000_00000111;
-                self.mem_read(mirrored_addr)
+                self.bus_read(mirrored_addr)
             }
 
-            0x4000..=0x4015 => {
-                //ignore APU 
+            0x4000..=0x4013 => {
+                // write-only APU registers
                 0
             }
 
+            0x4015 => self.apu.read_status(),
+
             0x4016 => {
                 self.joypad1.read()
             }
@@ -117,7 +403,7 @@ impl Mem for Bus<'_> {
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    fn bus_write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM_START..=RAM_MIRROR_END => {
                 let mirrored_addr = addr & 0b11111111111;
@@ -130,7 +416,7 @@ impl Mem for Bus<'_> {
                 self.ppu.write_to_mask(data);
             }
 
-            0x2002 => panic!("attempt to write to PPU status register"),
+            0x2002 => self.ppu.write_to_status(data),
 
             0x2003 => {
                 self.ppu.write_to_oam_addr(data);
@@ -149,39 +435,29 @@ impl Mem for Bus<'_> {
                 self.ppu.write_to_data(data);
             }
 
-            0x4000..=0x4013 | 0x4015 => {
-                //ignore APU 
-            }
+            0x4000..=0x4013 | 0x4015 => self.apu.write_register(addr, data),
 
             0x4016 => {
                 self.joypad1.write(data);
             }
 
-            0x4017 => {
-                // ignore joypad 2
-            }
+            // frame counter; joypad 2 only uses $4017 for reads
+            0x4017 => self.apu.write_register(addr, data),
 
             // https://wiki.nesdev.com/w/index.php/PPU_programmer_reference#OAM_DMA_.28.244014.29_.3E_write
             0x4014 => {
-                let mut buffer: [u8; 256] = [0; 256];
-                let hi: u16 = (data as u16) << 8;
-                for i in 0..256u16 {
-                    buffer[i as usize] = self.mem_read(hi + i);
-                }
-
-                self.ppu.write_oam_dma(&buffer);
-
-                // todo: handle this eventually
-                // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
-                // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
+                // copied by run_dma, after this instruction
+                self.oam_dma_page = Some(data);
             }
 
             0x2008..=PPU_REGISTERS_MIRROR_END => {
                 let mirrored_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirrored_addr, data);
+                self.bus_write(mirrored_addr, data);
                 // todo!("PPU is not supported yet");
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            // NROM has no registers to write; other mappers would pick up
+            // bank switches here
+            CARTRIDGE_ROM_START..=CARTRIDGE_ROM_END => {}
 
             _ => {
                 println!("Ignoring mem write-access at {}", addr);
@@ -189,3 +465,168 @@ impl Mem for Bus<'_> {
         }
     }
 }
+
+impl Savestate for Bus<'_> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.cpu_vram);
+        w.write_u64(self.cycles as u64);
+        w.write_u8(self.ppu_dot_fraction);
+        w.write_bool(self.dmc_dma.is_some());
+        w.write_u16(self.dmc_dma.unwrap_or(0));
+        w.write_bool(self.dmc_sample.is_some());
+        w.write_u8(self.dmc_sample.unwrap_or(0));
+        self.joypad1.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_into(&mut self.cpu_vram)?;
+        self.cycles = r.read_u64()? as usize;
+        self.ppu_dot_fraction = r.read_u8()?;
+        let dmc_pending = r.read_bool()?;
+        let dmc_addr = r.read_u16()?;
+        self.dmc_dma = if dmc_pending { Some(dmc_addr) } else { None };
+        let sample_ready = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.dmc_sample = if sample_ready { Some(sample) } else { None };
+        self.joypad1.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::test::test_rom;
+    use crate::ppu::PpuEvent;
+
+    fn test_bus<'a>() -> Bus<'a> {
+        Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {})
+    }
+
+    #[test]
+    fn test_oam_dma_stall() {
+        let mut bus = test_bus();
+        for i in 0..256u16 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.mem_write(0x2003, 0x10);
+
+        let mut stalls = Vec::new();
+        for _ in 0..2 {
+            let start = bus.cycles();
+            bus.mem_write(0x4014, 0x02);
+            bus.run_dma();
+            stalls.push(bus.cycles() - start);
+            bus.tick(1);
+        }
+        stalls.sort();
+        assert_eq!(stalls, vec![513, 514]);
+
+        // the copy starts at OAMADDR and wraps around
+        assert_eq!(bus.ppu().oam_data[0x10], 0x00);
+        assert_eq!(bus.ppu().oam_data[0x0f], 0xff);
+    }
+
+    #[test]
+    fn test_dmc_dma_stall() {
+        let mut bus = test_bus();
+        let start = bus.cycles();
+        bus.request_dmc_dma(0xc000);
+        bus.run_dma();
+        let stall = bus.cycles() - start;
+        assert!(stall == 3 || stall == 4);
+        assert_eq!(bus.take_dmc_sample(), Some(1));
+        assert_eq!(bus.take_dmc_sample(), None);
+
+        // during OAM DMA it only costs two cycles
+        bus.tick((bus.cycles() % 2) as u8);
+        let start = bus.cycles();
+        bus.mem_write(0x4014, 0x02);
+        bus.request_dmc_dma(0xc000);
+        bus.run_dma();
+        assert_eq!(bus.cycles() - start, 514 + 2);
+        assert_eq!(bus.take_dmc_sample(), Some(1));
+    }
+
+    #[test]
+    fn test_apu_dmc_fetch_and_irqs() {
+        let mut bus = test_bus();
+        bus.mem_write(0x4010, 0x8f);
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+        assert_eq!(bus.mem_read(0x4015) & 0x10, 0x10);
+
+        bus.tick(1);
+        let start = bus.cycles();
+        bus.run_dma();
+        assert!(bus.cycles() - start >= 3);
+        bus.tick(1);
+        assert_eq!(bus.apu().dmc.bytes_remaining, 0);
+        assert!(bus.poll_irq_status());
+        assert_eq!(bus.peek(0x4015) & 0x90, 0x80);
+
+        // the frame counter IRQ is acknowledged by reading $4015
+        bus.mem_write(0x4015, 0x00);
+        assert!(!bus.poll_irq_status());
+        bus.tick(255);
+        while !bus.poll_irq_status() {
+            bus.tick(1);
+        }
+        assert_eq!(bus.mem_read(0x4015) & 0x40, 0x40);
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_pal_dot_ratio() {
+        let mut bus = test_bus();
+        bus.set_region(Region::Pal);
+        for _ in 0..5 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu().cycle(), 16);
+
+        bus.set_region(Region::Ntsc);
+        bus.tick(5);
+        assert_eq!(bus.ppu().cycle(), 31);
+    }
+
+    #[test]
+    fn test_ppu_event_log() {
+        let mut bus = test_bus();
+        bus.ppu_mut().set_event_log(true);
+        bus.tick(100);
+        bus.mem_write(0x2005, 0x10);
+        bus.mem_write(0x0200, 0x10);
+        bus.tick(1);
+        bus.mem_write(0x4014, 0x02);
+
+        // the log is handed over once the frame is done
+        assert!(bus.ppu().events().is_empty());
+        while bus.ppu().scanline() != 241 || bus.ppu().cycle() < 2 {
+            bus.tick(1);
+        }
+        let events = bus.ppu().events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], PpuEvent { addr: 0x2005, value: 0x10, scanline: 0, dot: 300 });
+        assert_eq!((events[1].addr, events[1].dot), (0x4014, 303));
+    }
+
+    #[test]
+    fn test_mapper_write_logged() {
+        let mut bus = test_bus();
+        bus.ppu_mut().set_event_log(true);
+        bus.tick(100);
+        bus.mem_write(0x8000, 0x05);
+        // ROM stays as it was
+        assert_eq!(bus.mem_read(0x8000), 1);
+
+        while bus.ppu().scanline() != 241 || bus.ppu().cycle() < 2 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu().events(), &[PpuEvent { addr: 0x8000, value: 0x05, scanline: 0, dot: 300 }]);
+    }
+}
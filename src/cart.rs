@@ -1,5 +1,8 @@
 // Supports .NES files
 
+use crate::graphics_data::png::crc32;
+use crate::region::Region;
+
 /// # Control Byte 1 https://www.nesdev.org/wiki/INES
 /// 76543210
 /// ||||||||
@@ -9,7 +12,6 @@
 /// |||||+--- 1: 512-byte trainer at $7000-$71FF (stored before PRG data)
 /// ||||+---- 1: Ignore mirroring control or above mirroring bit; instead provide four-screen VRAM
 /// ++++----- Four lower bits of mapper number
-
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM: usize = 0x4000;
 const CHR_ROM: usize = 0x2000;
@@ -26,7 +28,9 @@ pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
-    pub screen_mirroring: Mirroring
+    pub screen_mirroring: Mirroring,
+    // Timing from a NES 2.0 header; iNES 1.0 headers don't reliably carry one
+    pub region: Option<Region>,
 }
 
 impl Rom {
@@ -39,8 +43,37 @@ impl Rom {
 
         // Checks .NES version
         let ines_ver = (rom_data[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 is not supported".to_string());
+        let nes2 = match ines_ver {
+            0 => false,
+            2 => true,
+            _ => return Err("Unsupported iNES version".to_string()),
+        };
+
+        // NES 2.0 https://www.nesdev.org/wiki/NES_2.0
+        // Byte 8 holds the upper mapper bits, byte 9 the upper ROM size bits and
+        // the low two bits of byte 12 the CPU/PPU timing
+        let mut prg_banks = rom_data[4] as usize;
+        let mut chr_banks = rom_data[5] as usize;
+        let mut region = None;
+        if nes2 {
+            if rom_data[8] & 0b1111 != 0 {
+                return Err("Mappers above 255 are not supported".to_string());
+            }
+            let prg_msb = rom_data[9] & 0b1111;
+            let chr_msb = rom_data[9] >> 4;
+            if prg_msb == 0xf || chr_msb == 0xf {
+                return Err("Exponent ROM sizes are not supported".to_string());
+            }
+            prg_banks |= (prg_msb as usize) << 8;
+            chr_banks |= (chr_msb as usize) << 8;
+
+            region = match rom_data[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                // Multi-region carts run fine on NTSC timing
+                2 => Some(Region::Ntsc),
+                _ => Some(Region::Dendy),
+            };
         }
 
         let four_screen = rom_data[6] & 0b1000 != 0;
@@ -53,8 +86,8 @@ impl Rom {
         };
 
         // Gets PRG and CHR ROM size
-        let prg_rom_size = rom_data[4] as usize * PRG_ROM;
-        let chr_rom_size = rom_data[5] as usize * CHR_ROM;
+        let prg_rom_size = prg_banks * PRG_ROM;
+        let chr_rom_size = chr_banks * CHR_ROM;
 
         // Checks for trainer
         let skip_trainer = rom_data[6] &0b100 != 0;
@@ -65,10 +98,17 @@ impl Rom {
         Ok(Rom {
             prg_rom: rom_data[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: rom_data[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            mapper,
+            screen_mirroring,
+            region,
         })
     }
+
+    // CRC32 of PRG and CHR ROM without the header, which is how ROM
+    // databases identify a dump
+    pub fn crc32(&self) -> u32 {
+        crc32(&[self.prg_rom.as_slice(), self.chr_rom.as_slice()].concat())
+    }
 }
 
 pub mod test {
@@ -167,10 +207,41 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 0x1, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM],
+            chr_rom: vec![2; 1 * CHR_ROM],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom.len(), PRG_ROM);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.region, Some(Region::Pal));
+        assert_eq!(test_rom_region(3), Some(Region::Dendy));
+        assert_eq!(super::test::test_rom().region, None);
+    }
+
+    #[cfg(test)]
+    fn test_rom_region(timing: u8) -> Option<Region> {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x8, 00, 00, 00, 00, timing, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM],
+            chr_rom: vec![],
+        });
+        Rom::new(&test_rom).unwrap().region
+    }
+
+    #[test]
+    fn test_unknown_ines_version_is_rejected() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x4, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM],
@@ -179,7 +250,7 @@ pub mod test {
         let rom = Rom::new(&test_rom);
         match rom {
             Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 is not supported"),
+            Result::Err(str) => assert_eq!(str, "Unsupported iNES version"),
         }
     }
-}
\ No newline at end of file
+}
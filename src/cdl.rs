@@ -0,0 +1,259 @@
+// Code/Data Logger, FCEUX compatible .cdl output.
+// https://fceux.com/web/help/CodeDataLogger.html
+//
+// The file is one flag byte per PRG ROM byte followed by one per CHR ROM byte.
+//
+// PRG:  xPdcAADC
+//       |||||||+- C: executed as code
+//       ||||||+-- D: read as data
+//       ||||++--- AA: CPU window the byte was mapped into ($8000 + AA * $2000)
+//       |||+----- c: data read through an indirect addressing mode
+//       ||+------ d: code reached through an indirect jump
+//       |+------- P: fetched as DMC sample (PCM) data
+//
+// CHR:  xxxxxxRD
+//             |+- D: fetched by the PPU while rendering
+//             +-- R: read by the CPU through $2007
+
+use crate::cpu::AddressingMode;
+use crate::opcodes;
+use std::io;
+use std::path::Path;
+
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+pub const PRG_PCM: u8 = 0x40;
+
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+// FCEUX has no room for this, so it is only kept in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChrLayer {
+    Background,
+    Sprite,
+}
+
+const LAYER_BACKGROUND: u8 = 0x01;
+const LAYER_SPRITE: u8 = 0x02;
+
+pub struct CodeDataLogger {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_layers: Vec<u8>,
+
+    // bytes of the instruction being executed, reads in here are code
+    instruction_start: u16,
+    instruction_end: u16,
+    indirect_code: bool,
+    indirect_data: bool,
+    last_opcode: u8,
+}
+
+impl CodeDataLogger {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLogger {
+            prg: vec![0; prg_len],
+            chr: vec![0; chr_len],
+            chr_layers: vec![0; chr_len],
+            instruction_start: 0,
+            instruction_end: 0,
+            indirect_code: false,
+            indirect_data: false,
+            last_opcode: 0,
+        }
+    }
+
+    // Continues logging on top of an existing .cdl file for the same ROM
+    pub fn from_bytes(data: &[u8], prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        if data.len() != prg_len + chr_len {
+            return Err(format!(
+                "cdl file is {} bytes, expected {} for this ROM",
+                data.len(),
+                prg_len + chr_len
+            ));
+        }
+        let mut cdl = CodeDataLogger::new(prg_len, chr_len);
+        cdl.prg.copy_from_slice(&data[..prg_len]);
+        cdl.chr.copy_from_slice(&data[prg_len..]);
+        Ok(cdl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        data
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn prg_flags(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr_flags(&self) -> &[u8] {
+        &self.chr
+    }
+
+    pub fn chr_layer(&self, offset: usize) -> Option<ChrLayer> {
+        match self.chr_layers.get(offset).copied().unwrap_or(0) {
+            0 => None,
+            LAYER_SPRITE => Some(ChrLayer::Sprite),
+            _ => Some(ChrLayer::Background),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg.is_empty() {
+            return None;
+        }
+        // mirrors like Bus::read_prg_rom for 16KB carts
+        Some((addr - 0x8000) as usize % self.prg.len())
+    }
+
+    fn mark_prg(&mut self, addr: u16, flags: u8) {
+        if let Some(offset) = self.prg_offset(addr) {
+            let bank = (((addr - 0x8000) >> 13) as u8 & 0b11) << 2;
+            self.prg[offset] |= flags | bank;
+        }
+    }
+
+    // Called by the CPU right before it fetches the opcode at `addr`
+    pub fn begin_instruction(&mut self, addr: u16) {
+        self.indirect_code = self.last_opcode == 0x6c;
+        self.indirect_data = false;
+        self.instruction_start = addr;
+        // exact length is known once the opcode byte comes by
+        self.instruction_end = addr.wrapping_add(3);
+    }
+
+    // Called for every CPU bus read
+    pub fn log_read(&mut self, addr: u16, value: u8) {
+        if addr == self.instruction_start {
+            if let Some(op) = opcodes::OPCODES_MAP.get(&value) {
+                self.instruction_end = addr.wrapping_add(op.len as u16);
+                self.indirect_data = matches!(
+                    op.mode,
+                    AddressingMode::Indirect_X | AddressingMode::Indirect_Y
+                );
+            }
+            self.last_opcode = value;
+        }
+
+        if addr >= self.instruction_start && addr < self.instruction_end {
+            let flags = if self.indirect_code {
+                PRG_CODE | PRG_INDIRECT_CODE
+            } else {
+                PRG_CODE
+            };
+            self.mark_prg(addr, flags);
+        } else if self.indirect_data {
+            self.mark_prg(addr, PRG_DATA | PRG_INDIRECT_DATA);
+        } else {
+            self.mark_prg(addr, PRG_DATA);
+        }
+    }
+
+    pub fn log_pcm(&mut self, addr: u16) {
+        self.mark_prg(addr, PRG_DATA | PRG_PCM);
+    }
+
+    pub fn log_chr_read(&mut self, addr: u16) {
+        if let Some(flags) = self.chr.get_mut(addr as usize) {
+            *flags |= CHR_READ;
+        }
+    }
+
+    // Marks a whole 16 byte tile as fetched by the renderer
+    pub fn log_chr_tile(&mut self, addr: u16, layer: ChrLayer) {
+        let layer = match layer {
+            ChrLayer::Background => LAYER_BACKGROUND,
+            ChrLayer::Sprite => LAYER_SPRITE,
+        };
+        for offset in addr as usize..addr as usize + 16 {
+            if offset < self.chr.len() {
+                self.chr[offset] |= CHR_DRAWN;
+                self.chr_layers[offset] |= layer;
+            }
+        }
+    }
+
+    // (code bytes, data bytes, drawn CHR bytes)
+    pub fn coverage(&self) -> (usize, usize, usize) {
+        (
+            self.prg.iter().filter(|f| *f & PRG_CODE != 0).count(),
+            self.prg.iter().filter(|f| *f & PRG_DATA != 0).count(),
+            self.chr.iter().filter(|f| *f & CHR_DRAWN != 0).count(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::cpu::{Mem, CPU};
+    use crate::ppu::MyPPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_code_and_data_split() {
+        let mut cdl = CodeDataLogger::new(0x8000, 0x2000);
+        // LDA $C010 at $8000
+        cdl.begin_instruction(0x8000);
+        cdl.log_read(0x8000, 0xad);
+        cdl.log_read(0x8001, 0x10);
+        cdl.log_read(0x8002, 0xc0);
+        cdl.log_read(0xc010, 0x00);
+
+        let prg = cdl.prg_flags();
+        assert_eq!(prg[0], PRG_CODE);
+        assert_eq!(prg[2], PRG_CODE);
+        assert_eq!(prg[0x4010], PRG_DATA | (2 << 2));
+    }
+
+    #[test]
+    fn test_indirect_flags() {
+        let mut cdl = CodeDataLogger::new(0x8000, 0x2000);
+        // LDA ($10),Y reading $9000, then JMP ($0200) landing on $A000
+        cdl.begin_instruction(0x8000);
+        cdl.log_read(0x8000, 0xb1);
+        cdl.log_read(0x8001, 0x10);
+        cdl.log_read(0x9000, 0x00);
+        cdl.begin_instruction(0x8002);
+        cdl.log_read(0x8002, 0x6c);
+        cdl.begin_instruction(0xa000);
+        cdl.log_read(0xa000, 0xea);
+
+        let prg = cdl.prg_flags();
+        assert_eq!(prg[0x1000], PRG_DATA | PRG_INDIRECT_DATA);
+        assert_eq!(prg[0x2000], PRG_CODE | PRG_INDIRECT_CODE | (1 << 2));
+    }
+
+    #[test]
+    fn test_cpu_hook() {
+        let rom = test_rom();
+        let cdl = Rc::new(RefCell::new(CodeDataLogger::new(rom.prg_rom.len(), rom.chr_rom.len())));
+        let mut bus = Bus::new(rom, |_: &MyPPU, _: &mut Joypad| {});
+        bus.attach_cdl(cdl.clone());
+        // LDA $8123 from RAM
+        bus.mem_write(0x0600, 0xad);
+        bus.mem_write(0x0601, 0x23);
+        bus.mem_write(0x0602, 0x81);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+
+        let bytes = cdl.borrow().to_bytes();
+        assert_eq!(bytes.len(), 0x8000 + 0x2000);
+        assert_eq!(bytes[0x123], PRG_DATA);
+        assert_eq!(cdl.borrow().coverage(), (0, 1, 0));
+    }
+}
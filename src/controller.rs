@@ -1,3 +1,5 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 bitflags! {
     // https://wiki.nesdev.com/w/index.php/Controller_reading_code
     pub struct JoypadButton: u8 {
@@ -48,6 +50,29 @@ impl Joypad {
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.strobe);
+        w.write_u8(self.button_index);
+        w.write_u8(self.button_status.bits);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.strobe = r.read_bool()?;
+        self.button_index = r.read_u8()?;
+        self.button_status = JoypadButton::from_bits_truncate(r.read_u8()?);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
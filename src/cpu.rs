@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use crate::opcodes;
 use crate::bus::Bus;
 use crate::trace;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 bitflags! {
     /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
@@ -39,6 +40,7 @@ pub struct CPU<'a> {
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub bus: Bus<'a>,
+    pub last_interrupt: Option<interrupt::InterruptType>,
     // memory: [u8; 0xFFFF]
 }
 
@@ -57,10 +59,11 @@ pub enum AddressingMode {
     NoneAddressing,
 }
 
-mod interrupt {
-    #[derive(PartialEq, Eq)]
+pub mod interrupt {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
@@ -76,6 +79,12 @@ mod interrupt {
         b_flag_mask: 0b00100000,
         cpu_cycles: 2,
     };
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 2,
+    };
 }
 
 
@@ -132,6 +141,7 @@ impl<'a> CPU<'a> {
             program_counter: 0,
             stack_pointer: STACK_RESET,
             bus: bus,
+            last_interrupt: None,
             // memory: [0; 0xFFFF]
         }
     }
@@ -195,7 +205,7 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn interrupt_nmi(&mut self, interrupt: interrupt::Interrupt) {
+    fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
         let mut flag = self.status.clone();
         flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
@@ -204,8 +214,9 @@ impl<'a> CPU<'a> {
         self.stack_push(flag.bits);
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
 
-        self.bus.tick(2);
-        self.program_counter = self.mem_read_u16(0xFFFA);
+        self.bus.tick(interrupt.cpu_cycles);
+        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+        self.last_interrupt = Some(interrupt.itype);
     }
 
     fn interrupt_brk(&mut self) {
@@ -883,384 +894,432 @@ impl<'a> CPU<'a> {
     where 
         F: FnMut(&mut CPU),
     {
-       //let mut i = 1;
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi(interrupt::NMI);
-            }
-
-            callback(self);
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
-            match code {
-                /* CLC */
-                0x18 => {
-                    self.status.remove(CpuFlags::CARRY);
-                },
-
-                /* CLD */
-                0xd8 => {
-                    self.status.remove(CpuFlags::DECIMAL_MODE);
-                },
-
-                /* CLI */ 
-                0x58 => {
-                    self.status.remove(CpuFlags::INTERRUPT_DISABLE);
-                },
-
-                /* CLV */ 
-                0xb8 => {
-                    self.status.remove(CpuFlags::OVERFLOW);
-                },
-
-                /* PHA */
-                0x48 => {
-                    self.stack_push(self.register_a);
-                },
-
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                },
-
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                },
-
-                /* ASL */
-                0x0a => {
-                    self.asl_accumulator();
-                },
-                
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                },
-
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                },
-
-                /* BCS */
-                0xb0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                },
-
-                /* BCE */
-                0xf0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                },
-
-                /* BIT */
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                },
-
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIVE));
-                },
-
-                /* BNE */
-                0xd0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                },
-
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIVE));
-                },
-
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                },
-
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                },
-                /* CMP */
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a);
-                },
-
-                /* CPX */
-                0xe0 | 0xe4 | 0xec => {
-                    self.compare(&opcode.mode, self.register_x);
-                },
-
-                /* CPY */
-                0xc0 | 0xc4 | 0xcc => {
-                    self.compare(&opcode.mode, self.register_y);
-                },
-
-                /* DEC */
-                0xc6 | 0xd6 | 0xce | 0xde => {
-                    self.dec(&opcode.mode);
-                },
-
-                /* DEX */ 
-                0xca => {
-                    self.dex();
-                },
-
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                },
-
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                },
-
-                /* INC */
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                },
-
-                /* INX */
-                0xe8 => {
-                    self.inx();
-                },
-
-                /* INY */
-                0xc8 => {
-                    self.iny();
-                },
-
-                /* JMP */
-                0x4c => {
-                    self.jmp_absolute();
-                },
-                0x6c => {self.jump_indirect();
-                },
-                
-                /* JSR */
-                0x20 => {
-                    self.jsr();
-                },
-
-                /* LDA */
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                },
-
-                /* LDX */
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                    self.ldx(&opcode.mode);
-                },
-
-                /* LDY */
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                    self.ldy(&opcode.mode);
-                },
-
-                /* LSR */
-                0x4a => {
-                    self.lsr_accumulator();
-                },
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                },
-                
-                /* NOP */
-                0xea | 0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* do nothing */ }
-
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
-
-                /* NOP read*/
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                    // do nothing
-                },
-
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                },
-
-                /* PHP */
-                0x08 => {
-                    self.php();
-                },
-
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                },
-
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                },
-
-                /* ROL */
-                0x2a => {
-                    self.rol_accumulator();
-                },
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                },
-
-                /* ROR */
-                0x6a => {
-                    self.ror_accumulator();
-                },
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                },
-
-                /* RTI */
-                0x40 => {
-                    self.rti();
-                },
-
-                /* RTS */
-                0x60 => {
-                    self.rts();
-                },
-
-                /* SBC */
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 | 0xeb => {
-                    self.sbc(&opcode.mode);
-                },
-
-                /* SEC */
-                0x38 => {
-                    self.status.insert(CpuFlags::CARRY);
-                },
-
-                /* SED */
-                0xf8 => {
-                    self.status.insert(CpuFlags::DECIMAL_MODE);
-                },
-
-                /* SEI */
-                0x78 => {
-                    self.status.insert(CpuFlags::INTERRUPT_DISABLE);
-                },
-
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                },
-
-                /* STX */
-                0x86 | 0x96 | 0x8e => {
-                    self.stx(&opcode.mode);
-                },
-
-                /* STY */
-                0x84 | 0x94 | 0x8c => {
-                    self.sty(&opcode.mode);
-                },
-
-                /* TXA */
-                0x8a => {
-                    self.txa();
-                },
-
-                /* TAX */
-                0xAA => {
-                    self.tax();
-                },
-
-                /* TAY */
-                0xa8 => {
-                    self.tay();
-                },
-
-                /* TSX */
-                0xba => {
-                    self.tsx();
-                },
-
-                /* TXS */
-                0x9a => {
-                    self.txs();
-                },
-
-                /* TYA */
-                0x98 => {
-                    self.tya();
-                },
-
-                0x00 => self.interrupt_brk(),
-
-                /* ILLEGAL OPCODES */
-
-                /* ANC */
-                0x0b => self.anc(&opcode.mode),
-
-                /* ASR */
-                0x4b => self.asr(&opcode.mode),
-
-                /* AXS */
-                0xcb => self.axs(&opcode.mode),
-                
-                /* DCP */
-                0xd3 | 0xdb | 0xcf | 0xdf | 0xc7 | 0xd7 | 0xc3 => {
-                    self.dcp(&opcode.mode);
-                }
+            self.step_with_callback(&mut callback);
+        }
+    }
 
-                /* ISB */
-                0xef | 0xff | 0xfb | 0xe7 | 0xf7 | 0xe3 | 0xf3 => {
-                    self.isb(&opcode.mode);
-                }
+    pub fn step(&mut self) {
+        self.step_with_callback(|_| {});
+    }
 
-                /* LAX */
-                0xb3 | 0xa7 | 0xa3 | 0xaf | 0xb7 | 0xbf => {
-                    self.lax(&opcode.mode);
-                }
-                
-                /* RLA */
-                0x2f | 0x3f | 0x3b | 0x27 | 0x37 | 0x23 | 0x33 => {
-                    self.rla(&opcode.mode);
-                }
+    // Services a pending interrupt (if any), hands control to the callback
+    // and then executes exactly one instruction.
+    pub fn step_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU),
+    {
+        self.service_interrupts();
+        callback(self);
+        self.execute();
+    }
 
-                /* RRA */
-                0x6f | 0x7f | 0x7b | 0x67 | 0x77 | 0x63 | 0x73 => {
-                    self.rra(&opcode.mode);
-                }
+    pub fn service_interrupts(&mut self) {
+        self.last_interrupt = None;
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupt::NMI);
+        } else if self.bus.poll_irq_status() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            self.interrupt(interrupt::IRQ);
+        }
+    }
 
-                /* SAX */
-                0x8f | 0x83 | 0x97 | 0x87 => self.sax(&opcode.mode),
+    // Fetches and executes the instruction at the program counter
+    pub fn execute(&mut self) {
+        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
-                /* SLO */
-                0x07 | 0x0f | 0x1f | 0x1b | 0x17 | 0x03 | 0x13 => {
-                    self.slo(&opcode.mode);
+        self.bus.begin_instruction(self.program_counter);
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+
+        let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
+        self.bus.set_instruction_cycles(opcode.cycles);
+        match code {
+            /* CLC */
+            0x18 => {
+                self.status.remove(CpuFlags::CARRY);
+            },
+
+            /* CLD */
+            0xd8 => {
+                self.status.remove(CpuFlags::DECIMAL_MODE);
+            },
+
+            /* CLI */ 
+            0x58 => {
+                self.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            },
+
+            /* CLV */ 
+            0xb8 => {
+                self.status.remove(CpuFlags::OVERFLOW);
+            },
+
+            /* PHA */
+            0x48 => {
+                self.stack_push(self.register_a);
+            },
+
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            },
+
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            },
+
+            /* ASL */
+            0x0a => {
+                self.asl_accumulator();
+            },
+            
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            },
+
+            /* BCC */
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            },
+
+            /* BCS */
+            0xb0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            },
+
+            /* BCE */
+            0xf0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            },
+
+            /* BIT */
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            },
+
+            /* BMI */
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIVE));
+            },
+
+            /* BNE */
+            0xd0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            },
+
+            /* BPL */
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIVE));
+            },
+
+            /* BVC */
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            },
+
+            /* BVS */
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            },
+            /* CMP */
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            },
+
+            /* CPX */
+            0xe0 | 0xe4 | 0xec => {
+                self.compare(&opcode.mode, self.register_x);
+            },
+
+            /* CPY */
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            },
+
+            /* DEC */
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            },
+
+            /* DEX */ 
+            0xca => {
+                self.dex();
+            },
+
+            /* DEY */
+            0x88 => {
+                self.dey();
+            },
+
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            },
+
+            /* INC */
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            },
+
+            /* INX */
+            0xe8 => {
+                self.inx();
+            },
+
+            /* INY */
+            0xc8 => {
+                self.iny();
+            },
+
+            /* JMP */
+            0x4c => {
+                self.jmp_absolute();
+            },
+            0x6c => {self.jump_indirect();
+            },
+            
+            /* JSR */
+            0x20 => {
+                self.jsr();
+            },
+
+            /* LDA */
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            },
+
+            /* LDX */
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            },
+
+            /* LDY */
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            },
+
+            /* LSR */
+            0x4a => {
+                self.lsr_accumulator();
+            },
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            },
+            
+            /* NOP */
+            0xea | 0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
+            | 0xf2 => { /* do nothing */ }
+
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+
+            /* NOP read*/
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
+            | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                if page_cross {
+                    self.bus.tick(1);
                 }
+                // do nothing
+            },
+
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            },
+
+            /* PHP */
+            0x08 => {
+                self.php();
+            },
+
+            /* PLA */
+            0x68 => {
+                self.pla();
+            },
+
+            /* PLP */
+            0x28 => {
+                self.plp();
+            },
+
+            /* ROL */
+            0x2a => {
+                self.rol_accumulator();
+            },
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            },
+
+            /* ROR */
+            0x6a => {
+                self.ror_accumulator();
+            },
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            },
+
+            /* RTI */
+            0x40 => {
+                self.rti();
+            },
+
+            /* RTS */
+            0x60 => {
+                self.rts();
+            },
+
+            /* SBC */
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 | 0xeb => {
+                self.sbc(&opcode.mode);
+            },
+
+            /* SEC */
+            0x38 => {
+                self.status.insert(CpuFlags::CARRY);
+            },
+
+            /* SED */
+            0xf8 => {
+                self.status.insert(CpuFlags::DECIMAL_MODE);
+            },
+
+            /* SEI */
+            0x78 => {
+                self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+            },
+
+            /* STA */
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            },
+
+            /* STX */
+            0x86 | 0x96 | 0x8e => {
+                self.stx(&opcode.mode);
+            },
+
+            /* STY */
+            0x84 | 0x94 | 0x8c => {
+                self.sty(&opcode.mode);
+            },
+
+            /* TXA */
+            0x8a => {
+                self.txa();
+            },
+
+            /* TAX */
+            0xAA => {
+                self.tax();
+            },
+
+            /* TAY */
+            0xa8 => {
+                self.tay();
+            },
+
+            /* TSX */
+            0xba => {
+                self.tsx();
+            },
+
+            /* TXS */
+            0x9a => {
+                self.txs();
+            },
+
+            /* TYA */
+            0x98 => {
+                self.tya();
+            },
+
+            0x00 => self.interrupt_brk(),
+
+            /* ILLEGAL OPCODES */
+
+            /* ANC */
+            0x0b => self.anc(&opcode.mode),
+
+            /* ASR */
+            0x4b => self.asr(&opcode.mode),
+
+            /* AXS */
+            0xcb => self.axs(&opcode.mode),
+            
+            /* DCP */
+            0xd3 | 0xdb | 0xcf | 0xdf | 0xc7 | 0xd7 | 0xc3 => {
+                self.dcp(&opcode.mode);
+            }
 
-                /* SRE */
-                0x4f | 0x5f | 0x5b | 0x47 | 0x57 | 0x43 | 0x53 => {
-                    self.sre(&opcode.mode);
-                }
+            /* ISB */
+            0xef | 0xff | 0xfb | 0xe7 | 0xf7 | 0xe3 | 0xf3 => {
+                self.isb(&opcode.mode);
+            }
+
+            /* LAX */
+            0xb3 | 0xa7 | 0xa3 | 0xaf | 0xb7 | 0xbf => {
+                self.lax(&opcode.mode);
+            }
+            
+            /* RLA */
+            0x2f | 0x3f | 0x3b | 0x27 | 0x37 | 0x23 | 0x33 => {
+                self.rla(&opcode.mode);
+            }
 
-                _ => todo!(),
+            /* RRA */
+            0x6f | 0x7f | 0x7b | 0x67 | 0x77 | 0x63 | 0x73 => {
+                self.rra(&opcode.mode);
             }
 
-            self.bus.tick(opcode.cycles);
+            /* SAX */
+            0x8f | 0x83 | 0x97 | 0x87 => self.sax(&opcode.mode),
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            /* SLO */
+            0x07 | 0x0f | 0x1f | 0x1b | 0x17 | 0x03 | 0x13 => {
+                self.slo(&opcode.mode);
             }
+
+            /* SRE */
+            0x4f | 0x5f | 0x5b | 0x47 | 0x57 | 0x43 | 0x53 => {
+                self.sre(&opcode.mode);
+            }
+
+            _ => todo!(),
         }
+
+        self.bus.tick(opcode.cycles);
+        self.bus.run_dma();
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+    }
+}
+impl Savestate for CPU<'_> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.register_a);
+        w.write_u8(self.register_x);
+        w.write_u8(self.register_y);
+        w.write_u8(self.status.bits);
+        w.write_u16(self.program_counter);
+        w.write_u8(self.stack_pointer);
+        self.bus.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.register_a = r.read_u8()?;
+        self.register_x = r.read_u8()?;
+        self.register_y = r.read_u8()?;
+        self.status = CpuFlags::from_bits_truncate(r.read_u8()?);
+        self.program_counter = r.read_u16()?;
+        self.stack_pointer = r.read_u8()?;
+        self.bus.load_state(r)
     }
 }
+
 /*
 #[cfg(test)]
 mod test {
@@ -0,0 +1,355 @@
+// Conditional expressions for breakpoints and watchpoints.
+//
+// Grammar (loosest binding first):
+//   expr    := or
+//   or      := and ("||" and)*
+//   and     := cmp ("&&" cmp)*
+//   cmp     := bitwise (("==" | "!=" | "<" | "<=" | ">" | ">=") bitwise)?
+//   bitwise := sum (("&" | "|" | "^") sum)*
+//   sum     := unary (("+" | "-") unary)*
+//   unary   := "!" unary | atom
+//   atom    := number | register | "[" expr "]" | "ppu[" expr "]" | "(" expr ")"
+//
+// Numbers are decimal, or hex when prefixed with `$` or `0x`. `[addr]` reads
+// a byte from CPU memory and `ppu[addr]` a byte from the PPU address space.
+// Registers: A, X, Y, P, SP, PC, SCANLINE (SL) and DOT.
+
+use crate::cpu::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+    Scanline,
+    Dot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Expr>),
+    PpuMemory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "&", "|", "^", "+", "-", "!", "[", "]", "(", ")",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = src.chars().collect();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c == '$' || c.is_ascii_digit() {
+            let (radix, start) = if c == '$' {
+                (16, i + 1)
+            } else if c == '0' && i + 1 < chars.len() && (chars[i + 1] == 'x' || chars[i + 1] == 'X') {
+                (16, i + 2)
+            } else {
+                (10, i)
+            };
+            let mut end = start;
+            while end < chars.len() && chars[end].is_digit(radix) {
+                end += 1;
+            }
+            let digits: String = chars[start..end].iter().collect();
+            let value = i64::from_str_radix(&digits, radix)
+                .map_err(|_| format!("invalid number at column {}", i + 1))?;
+            tokens.push(Token::Number(value));
+            i = end;
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = i;
+            while end < chars.len() && (chars[end].is_ascii_alphanumeric() || chars[end] == '_') {
+                end += 1;
+            }
+            tokens.push(Token::Ident(chars[i..end].iter().collect::<String>().to_ascii_uppercase()));
+            i = end;
+            continue;
+        }
+
+        for op in OPERATORS.iter() {
+            let len = op.len();
+            if i + len <= chars.len() && chars[i..i + len].iter().collect::<String>() == *op {
+                tokens.push(Token::Op(op));
+                i += len;
+                continue 'outer;
+            }
+        }
+
+        return Err(format!("unexpected character '{}' at column {}", c, i + 1));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn binary_level<F>(&mut self, ops: &[(&str, BinOp)], mut next: F) -> Result<Expr, String>
+    where
+        F: FnMut(&mut Parser) -> Result<Expr, String>,
+    {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            if let Some(found) = self.peek_op() {
+                for (text, op) in ops.iter() {
+                    if *text == found {
+                        self.pos += 1;
+                        let rhs = next(self)?;
+                        lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                        continue 'outer;
+                    }
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary_level(&[("||", BinOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary_level(&[("&&", BinOp::And)], Parser::cmp)
+    }
+
+    fn cmp(&mut self) -> Result<Expr, String> {
+        let lhs = self.bitwise()?;
+        let op = match self.peek_op() {
+            Some("==") => BinOp::Eq,
+            Some("!=") => BinOp::Ne,
+            Some("<") => BinOp::Lt,
+            Some("<=") => BinOp::Le,
+            Some(">") => BinOp::Gt,
+            Some(">=") => BinOp::Ge,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+        let rhs = self.bitwise()?;
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn bitwise(&mut self) -> Result<Expr, String> {
+        self.binary_level(
+            &[("&", BinOp::BitAnd), ("|", BinOp::BitOr), ("^", BinOp::BitXor)],
+            Parser::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary_level(&[("+", BinOp::Add), ("-", BinOp::Sub)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek_op() == Some("!") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Op("(") => {
+                let inner = self.or()?;
+                self.expect_op(")")?;
+                Ok(inner)
+            }
+            Token::Op("[") => {
+                let inner = self.or()?;
+                self.expect_op("]")?;
+                Ok(Expr::Memory(Box::new(inner)))
+            }
+            Token::Ident(name) => {
+                let register = match name.as_str() {
+                    "A" => Register::A,
+                    "X" => Register::X,
+                    "Y" => Register::Y,
+                    "P" => Register::P,
+                    "SP" => Register::SP,
+                    "PC" => Register::PC,
+                    "SL" | "SCANLINE" => Register::Scanline,
+                    "DOT" | "CYCLE" => Register::Dot,
+                    "PPU" => {
+                        self.expect_op("[")?;
+                        let inner = self.or()?;
+                        self.expect_op("]")?;
+                        return Ok(Expr::PpuMemory(Box::new(inner)));
+                    }
+                    _ => return Err(format!("unknown register '{}'", name)),
+                };
+                Ok(Expr::Register(register))
+            }
+            Token::Op(op) => Err(format!("unexpected '{}'", op)),
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    if parser.pos != parser.tokens.len() {
+        return Err("trailing input after expression".to_string());
+    }
+    Ok(expr)
+}
+
+impl Expr {
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(reg) => match reg {
+                Register::A => cpu.register_a as i64,
+                Register::X => cpu.register_x as i64,
+                Register::Y => cpu.register_y as i64,
+                Register::P => cpu.status.bits() as i64,
+                Register::SP => cpu.stack_pointer as i64,
+                Register::PC => cpu.program_counter as i64,
+                Register::Scanline => cpu.bus.ppu().scanline() as i64,
+                Register::Dot => cpu.bus.ppu().cycle() as i64,
+            },
+            Expr::Memory(addr) => cpu.bus.peek(addr.eval(cpu) as u16) as i64,
+            Expr::PpuMemory(addr) => cpu.bus.ppu().peek(addr.eval(cpu) as u16) as i64,
+            Expr::Not(inner) => (inner.eval(cpu) == 0) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval(cpu);
+                // short-circuit so `[x] && ...` style guards behave as expected
+                match op {
+                    BinOp::Or if l != 0 => return 1,
+                    BinOp::And if l == 0 => return 0,
+                    _ => {}
+                }
+                let r = rhs.eval(cpu);
+                match op {
+                    BinOp::Or | BinOp::And => (r != 0) as i64,
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::BitAnd => l & r,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::cpu::Mem;
+    use crate::ppu::MyPPU;
+
+    #[test]
+    fn test_parse_precedence() {
+        let expr = parse("a == 1 && x != $10 || y > 0x20").unwrap();
+        match expr {
+            Expr::Binary(BinOp::Or, lhs, _) => match *lhs {
+                Expr::Binary(BinOp::And, _, _) => {}
+                other => panic!("unexpected lhs {:?}", other),
+            },
+            other => panic!("unexpected expr {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("a ==").is_err());
+        assert!(parse("[$10").is_err());
+        assert!(parse("foo == 1").is_err());
+        assert!(parse("a 1").is_err());
+    }
+
+    #[test]
+    fn test_eval_registers_and_memory() {
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        bus.mem_write(0x10, 0x42);
+        let mut cpu = CPU::new(bus);
+        cpu.register_a = 5;
+        cpu.register_x = 0x10;
+
+        assert!(parse("A == 5").unwrap().is_true(&cpu));
+        assert!(parse("[X] == $42").unwrap().is_true(&cpu));
+        assert!(parse("[$10] & $F0 == $40").unwrap().is_true(&cpu));
+        assert!(!parse("!(a + 1 == 6)").unwrap().is_true(&cpu));
+        assert_eq!(parse("x - 1").unwrap().eval(&cpu), 0x0f);
+    }
+}
@@ -0,0 +1,610 @@
+// GDB remote serial protocol stub.
+// https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//
+// Register layout (also advertised through target.xml):
+//   0: A   8 bit
+//   1: X   8 bit
+//   2: Y   8 bit
+//   3: P   8 bit
+//   4: SP  8 bit
+//   5: PC  16 bit, little endian
+
+use super::{AddressSpace, Debugger, StopReason, WatchKind};
+use crate::bus::AccessKind;
+use crate::cpu::{CpuFlags, Mem, CPU};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes.m6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8" regnum="1"/>
+    <reg name="y" bitsize="8" type="uint8" regnum="2"/>
+    <reg name="p" bitsize="8" type="uint8" regnum="3"/>
+    <reg name="sp" bitsize="8" type="uint8" regnum="4"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="5"/>
+  </feature>
+</target>
+"#;
+
+// how many instructions run between checks for a ^C from the client
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+// largest packet we accept or send, advertised in qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+    // nothing complete yet on a non-blocking read
+    Pending,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    no_ack: bool,
+    debugger: Debugger,
+    breakpoints: HashMap<u16, usize>,
+    watchpoints: HashMap<(char, u16, u16), usize>,
+    // a continue/step is in flight and gdb is waiting for a stop reply
+    running: bool,
+    detached: bool,
+    // the client sent `k`; the emulator should shut down
+    killed: bool,
+    poll_counter: u32,
+}
+
+impl GdbStub {
+    // Blocks until a client connects. The target starts out halted, as
+    // gdb expects right after attaching.
+    pub fn accept(listener: &TcpListener, cpu: &mut CPU) -> io::Result<GdbStub> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        let mut debugger = Debugger::new();
+        debugger.attach(cpu);
+        debugger.pause();
+
+        Ok(GdbStub {
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+            debugger,
+            breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+            running: false,
+            detached: false,
+            killed: false,
+            poll_counter: 0,
+        })
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
+    // Called before every instruction, see `Debugger::check`.
+    pub fn on_instruction(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        if self.detached {
+            return Ok(());
+        }
+
+        self.poll_counter += 1;
+        if self.running && self.poll_counter >= INTERRUPT_POLL_INTERVAL {
+            self.poll_counter = 0;
+            if self.poll_interrupt()? {
+                self.debugger.pause();
+            }
+        }
+
+        if let Some(reason) = self.debugger.check(cpu) {
+            if self.running {
+                self.running = false;
+                let reply = stop_reply(&reason);
+                self.send(&reply)?;
+            }
+            self.serve(cpu)?;
+        }
+        Ok(())
+    }
+
+    // Handles packets until the client resumes execution or detaches.
+    fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        loop {
+            let packet = match self.read_incoming(true)? {
+                Some(Incoming::Packet(packet)) => packet,
+                Some(Incoming::Interrupt) | Some(Incoming::Pending) => continue,
+                None => {
+                    // connection closed, let the game run on
+                    self.detached = true;
+                    self.debugger.resume();
+                    return Ok(());
+                }
+            };
+
+            match self.handle_packet(cpu, &packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+            if self.detached {
+                return Ok(());
+            }
+        }
+    }
+
+    // Returns the reply to send, or None when execution resumes.
+    fn handle_packet(&mut self, cpu: &mut CPU, packet: &str) -> io::Result<Option<String>> {
+        let reply = match packet.chars().next() {
+            Some('?') => "S05".to_string(),
+            Some('g') => {
+                let regs = [
+                    cpu.register_a,
+                    cpu.register_x,
+                    cpu.register_y,
+                    cpu.status.bits(),
+                    cpu.stack_pointer,
+                    (cpu.program_counter & 0xff) as u8,
+                    (cpu.program_counter >> 8) as u8,
+                ];
+                to_hex(&regs)
+            }
+            Some('G') => match from_hex(&packet[1..]) {
+                Some(bytes) if bytes.len() >= 7 => {
+                    for (reg, byte) in bytes.iter().take(5).enumerate() {
+                        set_register(cpu, reg, *byte as u16);
+                    }
+                    set_register(cpu, 5, bytes[5] as u16 | (bytes[6] as u16) << 8);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            Some('p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(5) => to_hex(&cpu.program_counter.to_le_bytes()),
+                Ok(reg) if reg < 5 => to_hex(&[get_register(cpu, reg) as u8]),
+                _ => "E01".to_string(),
+            },
+            Some('P') => {
+                let mut parts = packet[1..].splitn(2, '=');
+                let reg = parts.next().and_then(|r| usize::from_str_radix(r, 16).ok());
+                let bytes = parts.next().and_then(from_hex);
+                match (reg, bytes) {
+                    (Some(reg), Some(bytes)) if reg <= 5 && !bytes.is_empty() => {
+                        let value = bytes
+                            .iter()
+                            .rev()
+                            .fold(0u16, |acc, b| acc << 8 | *b as u16);
+                        set_register(cpu, reg, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            Some('m') => match parse_addr_len(&packet[1..]) {
+                Some((addr, len)) => {
+                    // peek so looking at $2002 or $4016 doesn't disturb the
+                    // game; gdb asks again for whatever a short reply leaves out
+                    let len = len.min(PACKET_SIZE / 2);
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|i| cpu.bus.peek(addr.wrapping_add(i as u16)))
+                        .collect();
+                    to_hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            Some('M') => {
+                let mut parts = packet[1..].splitn(2, ':');
+                let target = parts.next().and_then(parse_addr_len);
+                let data = parts.next().and_then(from_hex);
+                match (target, data) {
+                    (Some((addr, len)), Some(data)) if data.len() == len => {
+                        let end = addr as usize + len;
+                        if end > 0x8000 && len > 0 {
+                            // cartridge ROM is not writable through the bus
+                            "E03".to_string()
+                        } else {
+                            for (i, byte) in data.iter().enumerate() {
+                                cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+                            }
+                            // the client's writes, and whatever they made the
+                            // PPU do, aren't the game's
+                            cpu.bus.take_access_log();
+                            cpu.bus.ppu_mut().take_access_log();
+                            "OK".to_string()
+                        }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            Some('Z') | Some('z') => self.handle_breakpoint(packet),
+            Some('c') => {
+                if let Ok(addr) = u16::from_str_radix(&packet[1..], 16) {
+                    cpu.program_counter = addr;
+                }
+                self.debugger.resume();
+                self.running = true;
+                return Ok(None);
+            }
+            Some('s') => {
+                if let Ok(addr) = u16::from_str_radix(&packet[1..], 16) {
+                    cpu.program_counter = addr;
+                }
+                self.debugger.step_into(1);
+                self.running = true;
+                return Ok(None);
+            }
+            Some('D') => {
+                self.send("OK")?;
+                self.detach();
+                return Ok(None);
+            }
+            Some('k') => {
+                // no reply; the main loop shuts down as if the window closed
+                self.detach();
+                self.killed = true;
+                return Ok(None);
+            }
+            Some('H') => "OK".to_string(),
+            Some('T') => "OK".to_string(),
+            Some('q') | Some('Q') => self.handle_query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            // the packet itself was already acked, and replies never wait
+            // for an ack, so the switch can happen right away
+            self.no_ack = true;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_addr_len(args) {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len).min(TARGET_XML.len());
+                    let prefix = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", prefix, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    // Z0/Z1: breakpoints, Z2: write, Z3: read, Z4: access watchpoints
+    fn handle_breakpoint(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let mut parts = packet[1..].split(',');
+        let kind = parts.next().and_then(|k| k.chars().next());
+        let addr = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        let len = parts
+            .next()
+            .and_then(|l| u16::from_str_radix(l.split(';').next().unwrap_or(l), 16).ok())
+            .unwrap_or(1)
+            .max(1);
+
+        let (kind, addr) = match (kind, addr) {
+            (Some(kind), Some(addr)) => (kind, addr),
+            _ => return "E01".to_string(),
+        };
+
+        match kind {
+            '0' | '1' => {
+                if insert {
+                    if !self.breakpoints.contains_key(&addr) {
+                        let id = self.debugger.add_breakpoint(addr, None);
+                        self.breakpoints.insert(addr, id);
+                    }
+                } else if let Some(id) = self.breakpoints.remove(&addr) {
+                    self.debugger.remove(id);
+                }
+                "OK".to_string()
+            }
+            '2' | '3' | '4' => {
+                let watch = match kind {
+                    '2' => WatchKind::WRITE,
+                    '3' => WatchKind::READ,
+                    _ => WatchKind::READ | WatchKind::WRITE,
+                };
+                let end = addr.saturating_add(len - 1);
+                let key = (kind, addr, end);
+                if insert {
+                    if !self.watchpoints.contains_key(&key) {
+                        let id = self
+                            .debugger
+                            .add_watchpoint(AddressSpace::Cpu, addr, end, watch, None);
+                        self.watchpoints.insert(key, id);
+                    }
+                } else if let Some(id) = self.watchpoints.remove(&key) {
+                    self.debugger.remove(id);
+                }
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    pub fn detach(&mut self) {
+        for (_, id) in self.breakpoints.drain() {
+            self.debugger.remove(id);
+        }
+        for (_, id) in self.watchpoints.drain() {
+            self.debugger.remove(id);
+        }
+        self.debugger.resume();
+        self.detached = true;
+        self.running = false;
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, checksum);
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    // Non-blocking check for a pending ^C
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let result = self.read_incoming(false);
+        self.stream.set_nonblocking(false)?;
+        match result? {
+            Some(Incoming::Interrupt) => Ok(true),
+            // anything else mid-run is unexpected; drop it
+            _ => Ok(false),
+        }
+    }
+
+    fn read_incoming(&mut self, blocking: bool) -> io::Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = self.parse_buffer()? {
+                return Ok(Some(incoming));
+            }
+
+            let mut chunk = [0u8; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if !blocking && e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Some(Incoming::Pending))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn parse_buffer(&mut self) -> io::Result<Option<Incoming>> {
+        // skip acks and line noise up to the next packet or ^C
+        while let Some(&b) = self.buffer.first() {
+            match b {
+                b'$' => break,
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+
+        let hash = match self.buffer.iter().position(|b| *b == b'#') {
+            Some(pos) if self.buffer.len() >= pos + 3 => pos,
+            _ => return Ok(None),
+        };
+
+        let body = String::from_utf8_lossy(&self.buffer[1..hash]).to_string();
+        let checksum = std::str::from_utf8(&self.buffer[hash + 1..hash + 3])
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        self.buffer.drain(..hash + 3);
+
+        let expected = body.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        if checksum != Some(expected) {
+            if !self.no_ack {
+                self.stream.write_all(b"-")?;
+            }
+            return Ok(None);
+        }
+        if !self.no_ack {
+            self.stream.write_all(b"+")?;
+        }
+        Ok(Some(Incoming::Packet(body)))
+    }
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint(_, AddressSpace::Cpu, access) => {
+            let kind = match access.kind {
+                AccessKind::Write => "watch",
+                AccessKind::Read => "rwatch",
+            };
+            format!("T05{}:{:04x};", kind, access.addr)
+        }
+        StopReason::Pause => "S02".to_string(),
+        _ => "S05".to_string(),
+    }
+}
+
+fn get_register(cpu: &CPU, reg: usize) -> u16 {
+    match reg {
+        0 => cpu.register_a as u16,
+        1 => cpu.register_x as u16,
+        2 => cpu.register_y as u16,
+        3 => cpu.status.bits() as u16,
+        4 => cpu.stack_pointer as u16,
+        _ => cpu.program_counter,
+    }
+}
+
+fn set_register(cpu: &mut CPU, reg: usize, value: u16) {
+    match reg {
+        0 => cpu.register_a = value as u8,
+        1 => cpu.register_x = value as u8,
+        2 => cpu.register_y = value as u8,
+        3 => cpu.status = CpuFlags::from_bits_truncate(value as u8),
+        4 => cpu.stack_pointer = value as u8,
+        _ => cpu.program_counter = value,
+    }
+}
+
+fn parse_addr_len(text: &str) -> Option<(u16, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::ppu::MyPPU;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(port: u16) -> Client {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        fn send(&mut self, body: &str) {
+            let checksum = body.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+            write!(self.writer, "${}#{:02x}", body, checksum).unwrap();
+        }
+
+        fn request(&mut self, body: &str) -> String {
+            self.send(body);
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut raw = Vec::new();
+            self.reader.read_until(b'#', &mut raw).unwrap();
+            let mut checksum = [0u8; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            let text = String::from_utf8(raw).unwrap();
+            let start = text.find('$').unwrap();
+            text[start + 1..text.len() - 1].to_string()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let mut client = Client::connect(port);
+            let replies: Vec<String> = ["?", "g", "M10,2:abcd", "m10,2", "Z0,604,1", "c", "p1", "s", "p5"]
+                .iter()
+                .map(|packet| client.request(packet))
+                .collect();
+            client.request("D");
+            replies
+        });
+
+        // LDX #$05; INX; INX; INX
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        for (i, byte) in [0xa2, 0x05, 0xe8, 0xe8, 0xe8].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut stub = GdbStub::accept(&listener, &mut cpu).unwrap();
+        for _ in 0..100 {
+            stub.on_instruction(&mut cpu).unwrap();
+            if stub.is_detached() {
+                break;
+            }
+            cpu.step();
+        }
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "00000024fd0006");
+        assert_eq!(replies[2], "OK");
+        assert_eq!(replies[3], "abcd");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(replies[5], "S05");
+        assert_eq!(replies[6], "07");
+        assert_eq!(replies[7], "S05");
+        assert_eq!(replies[8], "0506");
+    }
+
+    #[test]
+    fn test_memory_read_and_kill() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let mut client = Client::connect(port);
+            let ppu = client.request("m2000,8");
+            let big = client.request("m0,ffff");
+            client.send("k");
+            (ppu, big)
+        });
+
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        bus.ppu_mut().status.set_vblank_status(true);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut stub = GdbStub::accept(&listener, &mut cpu).unwrap();
+        stub.on_instruction(&mut cpu).unwrap();
+        assert!(stub.is_killed());
+        assert!(stub.is_detached());
+
+        let (ppu, big) = client.join().unwrap();
+        // the status read showed vblank without clearing it
+        assert_eq!(u8::from_str_radix(&ppu[4..6], 16).unwrap() & 0x80, 0x80);
+        assert_eq!(cpu.bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(big.len(), PACKET_SIZE);
+    }
+}
@@ -0,0 +1,268 @@
+// Execution history for reverse debugging.
+//
+// Every `interval` instructions a full machine snapshot is taken. Together
+// with a log of controller state changes this is enough to deterministically
+// re-run from a snapshot to any later instruction, which is how stepping
+// backwards works: restore the closest earlier snapshot, then replay forward
+// with the gameloop callback (rendering, host input) switched off.
+//
+// Positions are counted in instruction boundaries, i.e. the points at which
+// `CPU::run_with_callback` invokes its callback.
+
+use crate::controller::JoypadButton;
+use crate::cpu::CPU;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+const DEFAULT_INTERVAL: u64 = 5000;
+const DEFAULT_CAPACITY: usize = 600;
+
+struct Snapshot {
+    position: u64,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Instruction,
+    Scanline,
+    Frame,
+}
+
+pub struct History {
+    interval: u64,
+    capacity: usize,
+    position: u64,
+    snapshots: VecDeque<Snapshot>,
+    inputs: VecDeque<(u64, JoypadButton)>,
+    scanline_marks: VecDeque<u64>,
+    frame_marks: VecDeque<u64>,
+    last_buttons: JoypadButton,
+    last_scanline: u16,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        History::with_interval(DEFAULT_INTERVAL, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_interval(interval: u64, capacity: usize) -> Self {
+        History {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            position: 0,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            scanline_marks: VecDeque::new(),
+            frame_marks: VecDeque::new(),
+            last_buttons: JoypadButton::empty(),
+            last_scanline: 0,
+        }
+    }
+
+    // Index of the instruction boundary the CPU is currently sitting at
+    pub fn position(&self) -> u64 {
+        self.position.saturating_sub(1)
+    }
+
+    // Oldest position that can still be reached
+    pub fn earliest(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.position)
+    }
+
+    // Records the current instruction boundary. Must be called exactly once
+    // per boundary, before the instruction executes.
+    pub fn record(&mut self, cpu: &CPU) {
+        let position = self.position;
+
+        let buttons = cpu.bus.joypad1().buttons();
+        if position == 0 || buttons != self.last_buttons {
+            self.inputs.push_back((position, buttons));
+            self.last_buttons = buttons;
+        }
+
+        let scanline = cpu.bus.ppu().scanline();
+        if scanline != self.last_scanline {
+            self.scanline_marks.push_back(position);
+            if scanline < self.last_scanline {
+                self.frame_marks.push_back(position);
+            }
+            self.last_scanline = scanline;
+        }
+
+        if position.is_multiple_of(self.interval) {
+            let mut w = StateWriter::new();
+            cpu.save_state(&mut w);
+            self.snapshots.push_back(Snapshot {
+                position,
+                data: w.into_bytes(),
+            });
+            if self.snapshots.len() > self.capacity {
+                self.snapshots.pop_front();
+                self.trim();
+            }
+        }
+
+        self.position += 1;
+    }
+
+    fn trim(&mut self) {
+        let earliest = match self.earliest() {
+            Some(p) => p,
+            None => return,
+        };
+        // keep the last input change before the earliest snapshot, it is
+        // still in effect when replay starts from there
+        while self.inputs.len() > 1 && self.inputs[1].0 <= earliest {
+            self.inputs.pop_front();
+        }
+        while self.scanline_marks.front().is_some_and(|m| *m < earliest) {
+            self.scanline_marks.pop_front();
+        }
+        while self.frame_marks.front().is_some_and(|m| *m < earliest) {
+            self.frame_marks.pop_front();
+        }
+    }
+
+    // Position reached by stepping back `count` units from the current one
+    pub fn target(&self, unit: Unit, count: u64) -> Option<u64> {
+        let current = self.position();
+        let marks = match unit {
+            Unit::Instruction => return current.checked_sub(count),
+            Unit::Scanline => &self.scanline_marks,
+            Unit::Frame => &self.frame_marks,
+        };
+        let earlier: Vec<u64> = marks.iter().copied().filter(|m| *m < current).collect();
+        if count == 0 || earlier.len() < count as usize {
+            return None;
+        }
+        Some(earlier[earlier.len() - count as usize])
+    }
+
+    pub fn step_back(&mut self, cpu: &mut CPU, unit: Unit, count: u64) -> Result<u64, String> {
+        let target = self
+            .target(unit, count)
+            .ok_or("not enough history recorded to go back that far")?;
+        self.rewind_to(cpu, target)?;
+        Ok(target)
+    }
+
+    // Restores the machine to the instruction boundary `target` and drops
+    // everything recorded after it; execution continues on a new timeline.
+    pub fn rewind_to(&mut self, cpu: &mut CPU, target: u64) -> Result<(), String> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|s| s.position <= target)
+            .ok_or("target is older than the recorded history")?;
+
+        let mut r = StateReader::new(&snapshot.data);
+        cpu.load_state(&mut r)?;
+
+        cpu.bus.set_gameloop_enabled(false);
+        cpu.bus.apu_mut().set_output_enabled(false);
+        let mut position = snapshot.position;
+        while position < target {
+            cpu.execute();
+            cpu.service_interrupts();
+            position += 1;
+            if let Some((_, buttons)) = self.inputs.iter().find(|(p, _)| *p == position) {
+                cpu.bus.joypad1_mut().set_buttons(*buttons);
+            }
+        }
+        cpu.bus.set_gameloop_enabled(true);
+        cpu.bus.apu_mut().set_output_enabled(true);
+        cpu.bus.take_access_log();
+        cpu.bus.ppu_mut().take_access_log();
+
+        self.snapshots.retain(|s| s.position <= target);
+        self.inputs.retain(|(p, _)| *p <= target);
+        self.scanline_marks.retain(|m| *m <= target);
+        self.frame_marks.retain(|m| *m <= target);
+        self.last_buttons = cpu.bus.joypad1().buttons();
+        self.last_scanline = cpu.bus.ppu().scanline();
+        // the boundary we landed on counts as already recorded
+        self.position = target + 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::cpu::Mem;
+    use crate::apu::blip::Blip;
+    use crate::ppu::MyPPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_step_back_instructions() {
+        // loop: INX; STX $10; JMP loop
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        for (i, byte) in [0xe8, 0x86, 0x10, 0x4c, 0x00, 0x06].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut history = History::with_interval(4, 8);
+        for _ in 0..30 {
+            cpu.step_with_callback(|cpu| history.record(cpu));
+        }
+        assert_eq!(cpu.register_x, 10);
+
+        // sit at the next boundary, as the debugger would
+        cpu.service_interrupts();
+        history.record(&cpu);
+        assert_eq!(history.position(), 30);
+
+        history.step_back(&mut cpu, Unit::Instruction, 7).unwrap();
+        // boundary 23: eight INX and the following STX done, JMP pending
+        assert_eq!(history.position(), 23);
+        assert_eq!(cpu.register_x, 8);
+        assert_eq!(cpu.mem_read(0x10), 8);
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_replay_makes_no_audio() {
+        // loop: JMP loop
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        for (i, byte) in [0x4c, 0x00, 0x06].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        let blip = Rc::new(RefCell::new(Blip::new(1.0, 44100.0)));
+        bus.apu_mut().attach_blip(blip.clone());
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut history = History::with_interval(100, 8);
+        for _ in 0..1000 {
+            cpu.step_with_callback(|cpu| history.record(cpu));
+        }
+        cpu.service_interrupts();
+        history.record(&cpu);
+
+        let before = blip.borrow().samples_available();
+        assert!(before > 0);
+        history.step_back(&mut cpu, Unit::Instruction, 50).unwrap();
+        assert_eq!(blip.borrow().samples_available(), before);
+
+        // and it's back on for what runs next
+        for _ in 0..1000 {
+            cpu.step_with_callback(|cpu| history.record(cpu));
+        }
+        assert!(blip.borrow().samples_available() > before);
+    }
+}
@@ -0,0 +1,402 @@
+pub mod expr;
+pub mod gdb;
+pub mod history;
+pub mod repl;
+
+use crate::bus::{AccessKind, MemAccess};
+use crate::cpu::interrupt::InterruptType;
+use crate::cpu::CPU;
+use crate::trace::TraceLogger;
+use expr::Expr;
+use history::History;
+
+bitflags! {
+    pub struct WatchKind: u8 {
+        const READ    = 0b001;
+        const WRITE   = 0b010;
+        const EXECUTE = 0b100;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Cpu,
+    Ppu,
+}
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub space: AddressSpace,
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StopReason {
+    Breakpoint(usize),
+    Watchpoint(usize, AddressSpace, MemAccess),
+    Execute(usize),
+    Interrupt(InterruptType),
+    Scanline(u16),
+    Step,
+    Pause,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    Running,
+    Paused,
+    StepInto(usize),
+    // run until PC returns to `addr` with the stack at (or above) `sp`
+    StepOver { addr: u16, sp: u8 },
+    // run until an RTS/RTI pops the stack above `sp`
+    StepOut { sp: u8 },
+}
+
+// Debugger state that sits between instructions. `check` is meant to be
+// called from `CPU::run_with_callback`, i.e. after any pending interrupt
+// was serviced and before the next instruction is fetched.
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub break_on_nmi: bool,
+    pub break_on_irq: bool,
+    pub break_on_scanline: Option<u16>,
+    pub history: Option<History>,
+    pub trace: Option<TraceLogger>,
+    // set by the REPL, the frontend shuts down once it sees this
+    pub quit: bool,
+
+    mode: RunMode,
+    next_id: usize,
+    last_scanline: u16,
+    last_opcode: u8,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            break_on_nmi: false,
+            break_on_irq: false,
+            break_on_scanline: None,
+            history: None,
+            trace: None,
+            quit: false,
+            mode: RunMode::Running,
+            next_id: 1,
+            last_scanline: 0,
+            last_opcode: 0,
+        }
+    }
+
+    // Turns on bus access recording, which read/write watchpoints rely on.
+    pub fn attach(&mut self, cpu: &mut CPU) {
+        cpu.bus.set_access_log(true);
+        self.last_scanline = cpu.bus.ppu().scanline();
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16, condition: Option<Expr>) -> usize {
+        let id = self.allocate_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            condition,
+            enabled: true,
+        });
+        id
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        space: AddressSpace,
+        start: u16,
+        end: u16,
+        kind: WatchKind,
+        condition: Option<Expr>,
+    ) -> usize {
+        let id = self.allocate_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            space,
+            start,
+            end,
+            kind,
+            condition,
+            enabled: true,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        if let Some(b) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            b.enabled = enabled;
+            return true;
+        }
+        if let Some(w) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+            w.enabled = enabled;
+            return true;
+        }
+        false
+    }
+
+    fn allocate_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = RunMode::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        self.mode = RunMode::Running;
+    }
+
+    pub fn step_into(&mut self, count: usize) {
+        self.mode = RunMode::StepInto(count.max(1));
+    }
+
+    pub fn step_over(&mut self, cpu: &mut CPU) {
+        let pc = cpu.program_counter;
+        if cpu.bus.peek(pc) == 0x20 {
+            // JSR: run until the matching RTS lands right after it
+            self.mode = RunMode::StepOver {
+                addr: pc.wrapping_add(3),
+                sp: cpu.stack_pointer,
+            };
+        } else {
+            self.mode = RunMode::StepInto(1);
+        }
+    }
+
+    pub fn step_out(&mut self, cpu: &mut CPU) {
+        self.mode = RunMode::StepOut {
+            sp: cpu.stack_pointer,
+        };
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == RunMode::Paused
+    }
+
+    // Decides whether execution must stop before the instruction at the
+    // current PC. Read/write watchpoints are matched against the bus
+    // accesses made since the previous call, so they fire right after the
+    // offending instruction.
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<StopReason> {
+        if let Some(history) = self.history.as_mut() {
+            history.record(cpu);
+        }
+
+        let cpu_accesses = cpu.bus.take_access_log();
+        let ppu_accesses = cpu.bus.ppu_mut().take_access_log();
+        let scanline = cpu.bus.ppu().scanline();
+        let scanline_changed = scanline != self.last_scanline;
+        self.last_scanline = scanline;
+
+        let reason = self.find_stop_reason(cpu, &cpu_accesses, &ppu_accesses, scanline_changed);
+        self.last_opcode = cpu.bus.peek(cpu.program_counter);
+
+        if let Some(trace) = self.trace.as_mut() {
+            if let Some(StopReason::Breakpoint(id)) = reason {
+                trace.breakpoint_hit(id);
+            }
+            trace.log(cpu);
+        }
+
+        if reason.is_some() {
+            self.mode = RunMode::Paused;
+        }
+        reason
+    }
+
+    fn find_stop_reason(
+        &mut self,
+        cpu: &CPU,
+        cpu_accesses: &[MemAccess],
+        ppu_accesses: &[MemAccess],
+        scanline_changed: bool,
+    ) -> Option<StopReason> {
+        let pc = cpu.program_counter;
+
+        match self.mode {
+            RunMode::Paused => return Some(StopReason::Pause),
+            RunMode::StepInto(n) => {
+                if n <= 1 {
+                    return Some(StopReason::Step);
+                }
+                self.mode = RunMode::StepInto(n - 1);
+            }
+            RunMode::StepOver { addr, sp } => {
+                if pc == addr && cpu.stack_pointer >= sp {
+                    return Some(StopReason::Step);
+                }
+            }
+            RunMode::StepOut { sp } => {
+                let returned = self.last_opcode == 0x60 || self.last_opcode == 0x40;
+                if returned && cpu.stack_pointer > sp {
+                    return Some(StopReason::Step);
+                }
+            }
+            RunMode::Running => {}
+        }
+
+        if let Some(itype) = cpu.last_interrupt {
+            let wanted = match itype {
+                InterruptType::NMI => self.break_on_nmi,
+                InterruptType::IRQ => self.break_on_irq,
+            };
+            if wanted {
+                return Some(StopReason::Interrupt(itype));
+            }
+        }
+
+        if let Some(line) = self.break_on_scanline {
+            if scanline_changed && cpu.bus.ppu().scanline() == line {
+                return Some(StopReason::Scanline(line));
+            }
+        }
+
+        for w in self.watchpoints.iter().filter(|w| w.enabled) {
+            let accesses = match w.space {
+                AddressSpace::Cpu => cpu_accesses,
+                AddressSpace::Ppu => ppu_accesses,
+            };
+            let hit = accesses.iter().find(|a| {
+                let kind = match a.kind {
+                    AccessKind::Read => WatchKind::READ,
+                    AccessKind::Write => WatchKind::WRITE,
+                };
+                w.kind.contains(kind) && a.addr >= w.start && a.addr <= w.end
+            });
+            if let Some(access) = hit {
+                if condition_holds(&w.condition, cpu) {
+                    return Some(StopReason::Watchpoint(w.id, w.space, *access));
+                }
+            }
+            if w.space == AddressSpace::Cpu
+                && w.kind.contains(WatchKind::EXECUTE)
+                && pc >= w.start
+                && pc <= w.end
+                && condition_holds(&w.condition, cpu)
+            {
+                return Some(StopReason::Execute(w.id));
+            }
+        }
+
+        for b in self.breakpoints.iter().filter(|b| b.enabled) {
+            if b.addr == pc && condition_holds(&b.condition, cpu) {
+                return Some(StopReason::Breakpoint(b.id));
+            }
+        }
+
+        None
+    }
+}
+
+fn condition_holds(condition: &Option<Expr>, cpu: &CPU) -> bool {
+    match condition {
+        Some(expr) => expr.is_true(cpu),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::cpu::Mem;
+    use crate::ppu::MyPPU;
+
+    fn test_cpu<'a>(program: &[u8]) -> CPU<'a> {
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    fn run_until_stop(debugger: &mut Debugger, cpu: &mut CPU, limit: usize) -> Option<StopReason> {
+        for _ in 0..limit {
+            if let Some(reason) = debugger.check(cpu) {
+                return Some(reason);
+            }
+            cpu.step();
+        }
+        None
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // LDX #$00; loop: INX; JMP loop
+        let mut cpu = test_cpu(&[0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        let mut debugger = Debugger::new();
+        debugger.attach(&mut cpu);
+        debugger.add_breakpoint(0x0602, Some(expr::parse("x == 3").unwrap()));
+
+        let reason = run_until_stop(&mut debugger, &mut cpu, 100);
+        assert!(matches!(reason, Some(StopReason::Breakpoint(1))));
+        assert_eq!(cpu.register_x, 3);
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        // LDA #$07; NOP; STA $10; NOP
+        let mut cpu = test_cpu(&[0xa9, 0x07, 0xea, 0x85, 0x10, 0xea]);
+        let mut debugger = Debugger::new();
+        debugger.attach(&mut cpu);
+        debugger.add_watchpoint(AddressSpace::Cpu, 0x10, 0x10, WatchKind::WRITE, None);
+
+        match run_until_stop(&mut debugger, &mut cpu, 10) {
+            Some(StopReason::Watchpoint(_, AddressSpace::Cpu, access)) => {
+                assert_eq!(access.addr, 0x10);
+                assert_eq!(access.value, 0x07);
+            }
+            other => panic!("unexpected stop {:?}", other),
+        }
+        assert_eq!(cpu.program_counter, 0x0605);
+    }
+
+    #[test]
+    fn test_step_over_subroutine() {
+        // JSR $0604; NOP; INX; INX; RTS
+        let mut cpu = test_cpu(&[0x20, 0x04, 0x06, 0xea, 0xe8, 0xe8, 0x60]);
+        let mut debugger = Debugger::new();
+        debugger.attach(&mut cpu);
+        debugger.step_over(&mut cpu);
+
+        assert!(matches!(run_until_stop(&mut debugger, &mut cpu, 10), Some(StopReason::Step)));
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 2);
+    }
+}
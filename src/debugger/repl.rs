@@ -0,0 +1,484 @@
+use super::expr;
+use super::history::{History, Unit};
+use super::{AddressSpace, Debugger, StopReason, WatchKind};
+use crate::cpu::interrupt::InterruptType;
+use crate::apu::mixer::Channel;
+use crate::cpu::CPU;
+use crate::graphics_data::render;
+use crate::graphics_data::scope;
+use crate::trace::{disassemble, TraceFormat, TraceLogger};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+commands:
+  c, continue                    resume execution
+  s, step [n]                    step into (n instructions)
+  n, next                        step over subroutine calls
+  finish                         run until the current subroutine returns
+  back [scanline|frame] [n]      step backwards (needs history)
+  history <on|off>               record execution history for stepping back
+  b, break <addr> [if <expr>]    add a PC breakpoint
+  w, watch <r|w|rw|x> [ppu] <addr>[-<end>] [if <expr>]
+                                 add a watchpoint on CPU (or PPU) memory
+  catch <nmi|irq|off>            stop when an interrupt is serviced
+  scanline <n|off>               stop when the PPU reaches scanline n
+  d, delete <id>                 remove a breakpoint/watchpoint
+  enable <id>, disable <id>      toggle a breakpoint/watchpoint
+  l, list                        list breakpoints and watchpoints
+  r, regs                        show CPU and PPU registers
+  x [ppu] <addr> [len]           dump memory
+  p, print <expr>                evaluate an expression
+  dis [addr] [count]             disassemble
+  oam                            list the 64 sprites in OAM
+  pal                            show the 32 palette RAM entries
+  layer <bg|sprites> <on|off>    show or hide a layer in the picture
+  layer sprite <n> <on|off>      show or hide a single OAM entry
+  apu                            show the APU channel registers
+  mixer                          show the APU channel volumes
+  mixer <channel> <0-100|on|off> set a channel's volume or mute it
+  mixer solo <channel>|off       only play one channel, or all again
+  trace file <path>              log every instruction to a file
+  trace ring <n>                 keep the last n instructions in memory
+  trace dump <path>              write the ring buffer out (or flush the file)
+  trace <on|off>                 pause/resume tracing, 'trace off' keeps the log
+  trace stop                     close the trace log
+  trace range <start>-<end>|off  only trace PCs in this range
+  trace bank <n>|off             only trace code in this 16KB PRG bank
+  trace at <addr>                start tracing once PC reaches addr
+  trace after <id>               start tracing once breakpoint id is hit
+  trace ppu <on|off>             add PPU scanline/dot columns
+  trace cycles <on|off>          add a CPU cycle column
+  trace format <nestest|mesen>   output layout
+  q, quit                        exit the emulator";
+
+// Called before every instruction; drops into the prompt when the debugger
+// decides to stop.
+pub fn on_instruction(debugger: &mut Debugger, cpu: &mut CPU) {
+    if let Some(reason) = debugger.check(cpu) {
+        report(&reason);
+        println!("{}", disassemble(cpu, cpu.program_counter).0);
+        prompt(debugger, cpu);
+    }
+}
+
+fn report(reason: &StopReason) {
+    match reason {
+        StopReason::Breakpoint(id) => println!("breakpoint #{} hit", id),
+        StopReason::Watchpoint(id, space, access) => println!(
+            "watchpoint #{} hit: {:?} {:?} ${:04X} = ${:02X}",
+            id, space, access.kind, access.addr, access.value
+        ),
+        StopReason::Execute(id) => println!("execute watchpoint #{} hit", id),
+        StopReason::Interrupt(itype) => println!("{:?} serviced", itype),
+        StopReason::Scanline(line) => println!("reached scanline {}", line),
+        StopReason::Step | StopReason::Pause => {}
+    }
+}
+
+fn prompt(debugger: &mut Debugger, cpu: &mut CPU) {
+    let stdin = io::stdin();
+    loop {
+        print!("(nes) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            // stdin closed, nothing left to drive the debugger
+            debugger.quit = true;
+            debugger.resume();
+            return;
+        }
+
+        match execute(debugger, cpu, line.trim()) {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+// Runs one REPL command. Returns Ok(true) when execution should resume.
+pub fn execute(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> Result<bool, String> {
+    let (command, rest) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, ""),
+    };
+
+    match command {
+        "" => {}
+        "h" | "help" => println!("{}", HELP),
+        "c" | "continue" => {
+            debugger.resume();
+            return Ok(true);
+        }
+        "s" | "step" => {
+            let count = if rest.is_empty() { 1 } else { parse_number(rest)? as usize };
+            debugger.step_into(count);
+            return Ok(true);
+        }
+        "n" | "next" => {
+            debugger.step_over(cpu);
+            return Ok(true);
+        }
+        "finish" => {
+            debugger.step_out(cpu);
+            return Ok(true);
+        }
+        "back" => {
+            let history = debugger.history.as_mut().ok_or("history is off, see 'history on'")?;
+            let mut args = rest.split_whitespace().peekable();
+            let unit = match args.peek().copied() {
+                Some("scanline") => Unit::Scanline,
+                Some("frame") => Unit::Frame,
+                _ => Unit::Instruction,
+            };
+            if unit != Unit::Instruction {
+                args.next();
+            }
+            let count = match args.next() {
+                Some(n) => parse_number(n)? as u64,
+                None => 1,
+            };
+            let position = history.step_back(cpu, unit, count)?;
+            println!("at instruction #{}", position);
+            println!("{}", disassemble(cpu, cpu.program_counter).0);
+        }
+        "history" => match rest {
+            "on" => {
+                if debugger.history.is_none() {
+                    debugger.history = Some(History::new());
+                }
+            }
+            "off" => debugger.history = None,
+            _ => return Err("expected on or off".to_string()),
+        },
+        "b" | "break" => {
+            let (target, condition) = split_condition(rest)?;
+            let addr = parse_number(target)? as u16;
+            let id = debugger.add_breakpoint(addr, condition);
+            println!("breakpoint #{} at ${:04X}", id, addr);
+        }
+        "w" | "watch" => {
+            let (target, condition) = split_condition(rest)?;
+            let mut args = target.split_whitespace();
+            let kind = match args.next() {
+                Some("r") => WatchKind::READ,
+                Some("w") => WatchKind::WRITE,
+                Some("rw") => WatchKind::READ | WatchKind::WRITE,
+                Some("x") => WatchKind::EXECUTE,
+                _ => return Err("expected watch kind r, w, rw or x".to_string()),
+            };
+            let mut range = args.next().ok_or("missing address")?;
+            let space = if range.eq_ignore_ascii_case("ppu") {
+                range = args.next().ok_or("missing address")?;
+                AddressSpace::Ppu
+            } else {
+                AddressSpace::Cpu
+            };
+            if space == AddressSpace::Ppu && kind.contains(WatchKind::EXECUTE) {
+                return Err("execute watchpoints only apply to CPU memory".to_string());
+            }
+            let (start, end) = parse_range(range)?;
+            let id = debugger.add_watchpoint(space, start, end, kind, condition);
+            println!("watchpoint #{} on {:?} ${:04X}-${:04X}", id, space, start, end);
+        }
+        "catch" => match rest {
+            "nmi" => debugger.break_on_nmi = true,
+            "irq" => debugger.break_on_irq = true,
+            "off" => {
+                debugger.break_on_nmi = false;
+                debugger.break_on_irq = false;
+            }
+            _ => return Err("expected nmi, irq or off".to_string()),
+        },
+        "scanline" => {
+            debugger.break_on_scanline = if rest == "off" {
+                None
+            } else {
+                Some(parse_number(rest)? as u16)
+            };
+        }
+        "d" | "delete" => {
+            let id = parse_number(rest)? as usize;
+            if !debugger.remove(id) {
+                return Err(format!("no breakpoint #{}", id));
+            }
+        }
+        "enable" | "disable" => {
+            let id = parse_number(rest)? as usize;
+            if !debugger.set_enabled(id, command == "enable") {
+                return Err(format!("no breakpoint #{}", id));
+            }
+        }
+        "l" | "list" => list(debugger),
+        "r" | "regs" => print_registers(cpu),
+        "x" => {
+            let mut args = rest.split_whitespace().peekable();
+            let ppu = args.peek().is_some_and(|a| a.eq_ignore_ascii_case("ppu"));
+            if ppu {
+                args.next();
+            }
+            let addr = parse_number(args.next().ok_or("missing address")?)? as u16;
+            let len = match args.next() {
+                Some(n) => parse_number(n)? as u16,
+                None => 64,
+            };
+            dump(cpu, addr, len, ppu);
+        }
+        "p" | "print" => {
+            let value = expr::parse(rest)?.eval(cpu);
+            println!("{} (${:X})", value, value);
+        }
+        "dis" => {
+            let mut args = rest.split_whitespace();
+            let mut addr = match args.next() {
+                Some(a) => parse_number(a)? as u16,
+                None => cpu.program_counter,
+            };
+            let count = match args.next() {
+                Some(n) => parse_number(n)?,
+                None => 10,
+            };
+            for _ in 0..count {
+                let (text, len) = disassemble(cpu, addr);
+                println!("{}", text);
+                addr = addr.wrapping_add(len);
+            }
+        }
+        "oam" => {
+            for sprite in render::sprites(cpu.bus.ppu()) {
+                println!("{}", sprite);
+            }
+        }
+        "pal" => {
+            for entry in 0..32 {
+                println!("{}", render::palette_entry_info(cpu.bus.ppu(), entry));
+            }
+        }
+        "layer" => layer_command(cpu, rest)?,
+        "apu" => {
+            for channel in scope::CHANNELS.iter() {
+                println!("{}", scope::channel_info(cpu.bus.apu(), *channel).join(", "));
+            }
+        }
+        "mixer" => mixer_command(cpu, rest)?,
+        "trace" => return trace_command(debugger, rest).map(|_| false),
+        "q" | "quit" => {
+            debugger.quit = true;
+            debugger.resume();
+            return Ok(true);
+        }
+        _ => return Err(format!("unknown command '{}', try 'help'", command)),
+    }
+    Ok(false)
+}
+
+fn layer_command(cpu: &mut CPU, args: &str) -> Result<(), String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let ppu = cpu.bus.ppu_mut();
+    match args.as_slice() {
+        ["bg", state] => ppu.show_background = parse_switch(state)?,
+        ["sprites", state] => ppu.show_sprites = parse_switch(state)?,
+        ["sprite", index, state] => {
+            let index = parse_number(index)?;
+            if index >= 64 {
+                return Err(format!("no sprite #{}", index));
+            }
+            if parse_switch(state)? {
+                ppu.hidden_sprites &= !(1 << index);
+            } else {
+                ppu.hidden_sprites |= 1 << index;
+            }
+        }
+        _ => return Err("usage: layer <bg|sprites> <on|off>, layer sprite <n> <on|off>".to_string()),
+    }
+    Ok(())
+}
+
+fn mixer_command(cpu: &mut CPU, args: &str) -> Result<(), String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let mixer = &mut cpu.bus.apu_mut().mixer;
+    match args.as_slice() {
+        [] => {
+            for channel in Channel::ALL.iter() {
+                let state = if mixer.muted(*channel) { " (muted)" } else { "" };
+                println!("{:<10} {:>3}%{}", channel, (mixer.volume(*channel) * 100.0).round(), state);
+            }
+        }
+        ["solo", "off"] => {
+            for channel in Channel::ALL.iter() {
+                mixer.set_muted(*channel, false);
+            }
+        }
+        ["solo", channel] => mixer.solo(Channel::parse(channel)?),
+        [channel, "on"] => mixer.set_muted(Channel::parse(channel)?, false),
+        [channel, "off"] => mixer.set_muted(Channel::parse(channel)?, true),
+        [channel, volume] => {
+            let channel = Channel::parse(channel)?;
+            mixer.set_volume(channel, parse_number(volume)? as f32 / 100.0);
+        }
+        _ => return Err("usage: mixer [<channel> <0-100|on|off>], mixer solo <channel>|off".to_string()),
+    }
+    Ok(())
+}
+
+fn trace_command(debugger: &mut Debugger, args: &str) -> Result<(), String> {
+    let mut args = args.split_whitespace();
+    let command = args.next().unwrap_or("");
+    let arg = args.next();
+
+    match (command, arg) {
+        ("file", Some(path)) => {
+            let logger = TraceLogger::to_file(path).map_err(|e| format!("{}: {}", path, e))?;
+            debugger.trace = Some(logger);
+            return Ok(());
+        }
+        ("ring", Some(n)) => {
+            debugger.trace = Some(TraceLogger::ring_buffer(parse_number(n)? as usize));
+            return Ok(());
+        }
+        ("stop", None) => {
+            if let Some(mut trace) = debugger.trace.take() {
+                trace.flush().map_err(|e| e.to_string())?;
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let trace = debugger.trace.as_mut().ok_or("no trace log, see 'trace file' or 'trace ring'")?;
+    match (command, arg) {
+        ("on", None) => trace.set_enabled(true),
+        ("off", None) => trace.set_enabled(false),
+        ("dump", Some(path)) => trace.dump(path).map_err(|e| format!("{}: {}", path, e))?,
+        ("range", Some("off")) => trace.pc_range = None,
+        ("range", Some(range)) => trace.pc_range = Some(parse_range(range)?),
+        ("bank", Some("off")) => trace.bank = None,
+        ("bank", Some(n)) => trace.bank = Some(parse_number(n)? as u8),
+        ("at", Some(addr)) => trace.start_at_address(parse_number(addr)? as u16),
+        ("after", Some(id)) => trace.start_at_breakpoint(parse_number(id)? as usize),
+        ("ppu", Some(on)) => trace.show_ppu = parse_switch(on)?,
+        ("cycles", Some(on)) => trace.show_cycles = parse_switch(on)?,
+        ("format", Some("nestest")) => trace.format = TraceFormat::Nestest,
+        ("format", Some("mesen")) => trace.format = TraceFormat::Mesen,
+        _ => return Err("unknown trace command, try 'help'".to_string()),
+    }
+    Ok(())
+}
+
+fn parse_switch(text: &str) -> Result<bool, String> {
+    match text {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+fn split_condition(args: &str) -> Result<(&str, Option<expr::Expr>), String> {
+    match args.find(" if ") {
+        Some(idx) => Ok((args[..idx].trim(), Some(expr::parse(&args[idx + 4..])?))),
+        None => Ok((args.trim(), None)),
+    }
+}
+
+pub fn parse_number(text: &str) -> Result<i64, String> {
+    let text = text.trim();
+    let parsed = if let Some(hex) = text.strip_prefix('$') {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else {
+        text.parse::<i64>()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", text))
+}
+
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    match text.find('-') {
+        Some(idx) => {
+            let start = parse_number(&text[..idx])? as u16;
+            let end = parse_number(&text[idx + 1..])? as u16;
+            if end < start {
+                return Err("range end is before its start".to_string());
+            }
+            Ok((start, end))
+        }
+        None => {
+            let addr = parse_number(text)? as u16;
+            Ok((addr, addr))
+        }
+    }
+}
+
+fn list(debugger: &Debugger) {
+    for b in debugger.breakpoints.iter() {
+        println!(
+            "#{} break ${:04X}{}{}",
+            b.id,
+            b.addr,
+            if b.condition.is_some() { " (conditional)" } else { "" },
+            if b.enabled { "" } else { " [disabled]" }
+        );
+    }
+    for w in debugger.watchpoints.iter() {
+        println!(
+            "#{} watch {:?} {:?} ${:04X}-${:04X}{}{}",
+            w.id,
+            w.space,
+            w.kind,
+            w.start,
+            w.end,
+            if w.condition.is_some() { " (conditional)" } else { "" },
+            if w.enabled { "" } else { " [disabled]" }
+        );
+    }
+    if debugger.break_on_nmi {
+        println!("catch {:?}", InterruptType::NMI);
+    }
+    if debugger.break_on_irq {
+        println!("catch {:?}", InterruptType::IRQ);
+    }
+    if let Some(line) = debugger.break_on_scanline {
+        println!("scanline {}", line);
+    }
+}
+
+fn print_registers(cpu: &CPU) {
+    let ppu = cpu.bus.ppu();
+    println!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        cpu.program_counter
+    );
+    println!(
+        "PPU scanline:{} dot:{} ctrl:{:02X} mask:{:02X} status:{:02X} v:{:04X} t:{:04X} x:{} w:{} oam_addr:{:02X}",
+        ppu.scanline(),
+        ppu.cycle(),
+        ppu.control.bits(),
+        ppu.mask.bits(),
+        ppu.status.bits(),
+        ppu.loopy.v,
+        ppu.loopy.t,
+        ppu.loopy.x,
+        ppu.loopy.w as u8,
+        ppu.oam_addr
+    );
+}
+
+fn dump(cpu: &CPU, addr: u16, len: u16, ppu: bool) {
+    for row in (0..len).step_by(16) {
+        let base = addr.wrapping_add(row);
+        let bytes: Vec<String> = (0..16.min(len - row))
+            .map(|i| {
+                let a = base.wrapping_add(i);
+                let value = if ppu { cpu.bus.ppu().peek(a) } else { cpu.bus.peek(a) };
+                format!("{:02X}", value)
+            })
+            .collect();
+        println!("{:04X}: {}", base, bytes.join(" "));
+    }
+}
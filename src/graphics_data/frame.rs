@@ -1,14 +1,21 @@
 pub struct Frame {
     pub data: Vec<u8>,
+    // what the PPU actually outputs: 6 bit palette colour plus the three
+    // PPUMASK emphasis bits on top
+    pub pixels: Vec<u16>,
+    // NTSC colour subcarrier phase (in twelfths) of the first dot of the frame
+    pub phase: u8,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HEIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HEIGHT) * 3],
+            pixels: vec![0; Frame::WIDTH * Frame::HEIGHT],
+            phase: 0,
         }
     }
 
@@ -20,4 +27,10 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+
+    pub fn set_raw_pixel(&mut self, x: usize, y: usize, pixel: u16) {
+        if let Some(p) = self.pixels.get_mut(y * Frame::WIDTH + x) {
+            *p = pixel;
+        }
+    }
+}
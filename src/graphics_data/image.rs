@@ -0,0 +1,67 @@
+use super::png;
+
+// RGB24 picture of any size, what the debug viewers draw into
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let base = (y * self.width + x) * 3;
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+        for py in y..y + height {
+            for px in x..x + width {
+                self.set_pixel(px, py, rgb);
+            }
+        }
+    }
+
+    // Outline of a rectangle, wrapping around the edges of the image
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+        for i in 0..width {
+            self.set_pixel((x + i) % self.width, y % self.height, rgb);
+            self.set_pixel((x + i) % self.width, (y + height - 1) % self.height, rgb);
+        }
+        for i in 0..height {
+            self.set_pixel(x % self.width, (y + i) % self.height, rgb);
+            self.set_pixel((x + width - 1) % self.width, (y + i) % self.height, rgb);
+        }
+    }
+
+    // Copies `other` in at (x, y), every pixel blown up to scale x scale
+    pub fn blit(&mut self, other: &Image, x: usize, y: usize, scale: usize) {
+        for oy in 0..other.height * scale {
+            for ox in 0..other.width * scale {
+                self.set_pixel(x + ox, y + oy, other.pixel(ox / scale, oy / scale));
+            }
+        }
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, png::encode(self.width, self.height, &self.data))
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+}
@@ -0,0 +1,240 @@
+// Composite video emulation for NTSC consoles.
+// https://www.nesdev.org/wiki/NTSC_video
+//
+// The PPU doesn't output RGB: every dot is 8 samples of a square wave at
+// one of two voltage levels, its phase relative to the colour subcarrier
+// (12 samples per period) picking the hue. The TV separates luma and chroma
+// again by averaging over a window of samples, which is what smears colours
+// into their neighbours and, since the phase shifts every line and frame,
+// makes the dot crawl.
+//
+// Decoding is linear, so instead of generating and demodulating the signal
+// for every pixel, what each 9 bit pixel contributes to the RGB output of
+// itself and its neighbours is precomputed for the three phases a dot can
+// start on. Filtering a frame is then three table lookups per output sample.
+
+use super::frame::Frame;
+
+// Voltage levels of the low and high half of the wave for the four
+// brightness levels, relative to sync
+const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// emphasis bits pull the signal down during their third of the period
+const ATTENUATION: f32 = 0.746;
+
+const SAMPLES_PER_DOT: i32 = 8;
+const SUBCARRIER_PERIOD: i32 = 12;
+// phase offset (in twelfths) that lines the decoded hues up with the usual
+// palettes
+const HUE_OFFSET: f32 = 3.9;
+
+// Output samples per input pixel
+pub const SAMPLES_PER_PIXEL: usize = 2;
+pub const OUTPUT_WIDTH: usize = Frame::WIDTH * SAMPLES_PER_PIXEL;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscSettings {
+    // -1.0 (blurry, no dot crawl) to 1.0 (sharp, strong artifacts)
+    pub sharpness: f32,
+    // in degrees
+    pub hue: f32,
+    // 0.0 is greyscale, 1.0 normal
+    pub saturation: f32,
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        NtscSettings { sharpness: 0.0, hue: 0.0, saturation: 1.0 }
+    }
+}
+
+pub struct NtscFilter {
+    settings: NtscSettings,
+    // [start phase / 4][pixel][neighbour + 1][output sample] -> RGB
+    lut: Vec<[f32; 3]>,
+}
+
+// Is the wave for `color` high at subcarrier phase `phase`
+fn in_color_phase(color: u16, phase: i32) -> bool {
+    (color as i32 + phase).rem_euclid(SUBCARRIER_PERIOD) < 6
+}
+
+// Normalized signal level of `pixel` at subcarrier phase `phase`
+fn signal(pixel: u16, phase: i32) -> f32 {
+    let color = pixel & 0x0f;
+    let level = if color > 13 { 1 } else { ((pixel >> 4) & 3) as usize };
+    let emphasis = pixel >> 6;
+
+    let mut low = LEVELS[level];
+    let high = LEVELS[4 + level];
+    if color == 0 {
+        low = high;
+    }
+    let mut value = if color > 12 || !in_color_phase(color, phase) { low } else { high };
+
+    if (emphasis & 1 != 0 && in_color_phase(0, phase))
+        || (emphasis & 2 != 0 && in_color_phase(4, phase))
+        || (emphasis & 4 != 0 && in_color_phase(8, phase))
+    {
+        value *= ATTENUATION;
+    }
+    (value - BLACK) / (WHITE - BLACK)
+}
+
+// YIQ of a flat area of `pixel`, averaged over a whole subcarrier period
+pub fn decode(pixel: u16, hue: f32) -> (f32, f32, f32) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..SUBCARRIER_PERIOD {
+        let level = signal(pixel, phase) / SUBCARRIER_PERIOD as f32;
+        let angle = std::f32::consts::PI * (phase as f32 + HUE_OFFSET + hue / 30.0) / 6.0;
+        y += level;
+        i += level * angle.cos();
+        q += level * angle.sin();
+    }
+    (y, i, q)
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> [f32; 3] {
+    [
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    ]
+}
+
+fn lut_index(phase: usize, pixel: usize, neighbour: usize, sample: usize) -> usize {
+    ((phase * 512 + pixel) * 3 + neighbour) * SAMPLES_PER_PIXEL + sample
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> Self {
+        let mut filter = NtscFilter {
+            settings,
+            lut: vec![[0.0; 3]; 3 * 512 * 3 * SAMPLES_PER_PIXEL],
+        };
+        filter.build_lut();
+        filter
+    }
+
+    pub fn settings(&self) -> NtscSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: NtscSettings) {
+        self.settings = settings;
+        self.build_lut();
+    }
+
+    fn build_lut(&mut self) {
+        // a luma window shorter than the subcarrier period lets chroma
+        // through as fine detail
+        let sharpness = self.settings.sharpness.clamp(-1.0, 1.0);
+        let luma_window = (8.0 - 4.0 * sharpness).round() as i32;
+        let hue = HUE_OFFSET + self.settings.hue / 30.0;
+        let saturation = self.settings.saturation;
+
+        for phase in 0..3 {
+            for pixel in 0..512u16 {
+                for neighbour in -1..=1 {
+                    for sample in 0..SAMPLES_PER_PIXEL {
+                        // output sample centre, in signal samples from the
+                        // start of the middle pixel
+                        let centre = (2 * sample as i32 + 1) * SAMPLES_PER_DOT / (2 * SAMPLES_PER_PIXEL as i32);
+                        let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                        for offset in 0..SAMPLES_PER_DOT {
+                            let pos = neighbour * SAMPLES_PER_DOT + offset;
+                            let sample_phase = phase * 4 + pos;
+                            let level = signal(pixel, sample_phase);
+                            let distance = pos - centre;
+                            if distance >= -luma_window / 2 && distance < luma_window / 2 {
+                                y += level / luma_window as f32;
+                            }
+                            if (-SUBCARRIER_PERIOD / 2..SUBCARRIER_PERIOD / 2).contains(&distance) {
+                                let angle = std::f32::consts::PI * (sample_phase as f32 + hue) / 6.0;
+                                let level = level / SUBCARRIER_PERIOD as f32 * saturation;
+                                i += level * angle.cos();
+                                q += level * angle.sin();
+                            }
+                        }
+                        let rgb = yiq_to_rgb(y, i, q);
+                        let index = lut_index(phase as usize, pixel as usize, (neighbour + 1) as usize, sample);
+                        self.lut[index] = rgb;
+                    }
+                }
+            }
+        }
+    }
+
+    // Encodes and decodes `frame`, writing RGB24 rows of OUTPUT_WIDTH
+    // pixels to `out`
+    pub fn apply(&self, frame: &Frame, out: &mut [u8]) {
+        for y in 0..Frame::HEIGHT {
+            let row = &frame.pixels[y * Frame::WIDTH..(y + 1) * Frame::WIDTH];
+            for x in 0..Frame::WIDTH {
+                // pixel x of line y comes out on dot x + 1 of the line
+                let dot = (y * 341 + x + 1) as u64;
+                let phase = ((frame.phase as u64 + dot * 8) % 12 / 4) as usize;
+                let left = row[x.saturating_sub(1)] as usize;
+                let middle = row[x] as usize;
+                let right = row[(x + 1).min(Frame::WIDTH - 1)] as usize;
+
+                for sample in 0..SAMPLES_PER_PIXEL {
+                    let mut rgb = [0.0f32; 3];
+                    for (neighbour, pixel) in [left, middle, right].iter().enumerate() {
+                        let c = self.lut[lut_index(phase, *pixel, neighbour, sample)];
+                        rgb[0] += c[0];
+                        rgb[1] += c[1];
+                        rgb[2] += c[2];
+                    }
+                    let base = (y * OUTPUT_WIDTH + x * SAMPLES_PER_PIXEL + sample) * 3;
+                    for channel in 0..3 {
+                        out[base + channel] = (rgb[channel] * 255.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter_flat(filter: &NtscFilter, pixel: u16) -> (u8, u8, u8) {
+        let mut frame = Frame::new();
+        for p in frame.pixels.iter_mut() {
+            *p = pixel;
+        }
+        let mut out = vec![0; OUTPUT_WIDTH * Frame::HEIGHT * 3];
+        filter.apply(&frame, &mut out);
+        let base = (100 * OUTPUT_WIDTH + 100) * 3;
+        (out[base], out[base + 1], out[base + 2])
+    }
+
+    #[test]
+    fn test_greys_stay_grey() {
+        let filter = NtscFilter::new(NtscSettings::default());
+        for pixel in [0x00, 0x10, 0x20, 0x0f].iter() {
+            let (r, g, b) = filter_flat(&filter, *pixel);
+            assert_eq!(r, g);
+            assert_eq!(g, b);
+        }
+        assert_eq!(filter_flat(&filter, 0x0f), (0, 0, 0));
+        assert_eq!(filter_flat(&filter, 0x20), (255, 255, 255));
+    }
+
+    #[test]
+    fn test_hue_and_saturation() {
+        let mut filter = NtscFilter::new(NtscSettings { sharpness: -1.0, ..NtscSettings::default() });
+        // $16 is red, $1A green
+        let (r, g, b) = filter_flat(&filter, 0x16);
+        assert!(r > g && r > b);
+        let (r, g, b) = filter_flat(&filter, 0x1a);
+        assert!(g > r && g > b);
+
+        filter.set_settings(NtscSettings { saturation: 0.0, sharpness: -1.0, hue: 0.0 });
+        let (r, g, b) = filter_flat(&filter, 0x16);
+        assert!(r == g && g == b);
+    }
+}
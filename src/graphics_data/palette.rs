@@ -1,5 +1,7 @@
-#[rustfmt::skip]
+use crate::ppu::ppu_registers::ppu_mask::Color;
+use super::ntsc;
 
+#[rustfmt::skip]
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E), 
     (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), 
@@ -14,4 +16,156 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+// Each emphasis bit darkens the two colour channels it doesn't name
+pub fn emphasize(rgb: (u8, u8, u8), emphasis: &[Color]) -> (u8, u8, u8) {
+    let (mut r, mut g, mut b) = (rgb.0 as f32, rgb.1 as f32, rgb.2 as f32);
+    for color in emphasis {
+        match color {
+            Color::Red => {
+                g *= 0.816;
+                b *= 0.816;
+            }
+            Color::Green => {
+                r *= 0.816;
+                b *= 0.816;
+            }
+            Color::Blue => {
+                r *= 0.816;
+                g *= 0.816;
+            }
+        }
+    }
+    (r as u8, g as u8, b as u8)
+}
+
+// Colours of all 512 PPU outputs: the 64 palette entries under each of the
+// 8 emphasis combinations, indexed like the pixels in Frame
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteSettings {
+    // in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    // added to luma, 0.0 leaves it as is
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for PaletteSettings {
+    fn default() -> Self {
+        PaletteSettings { hue: 0.0, saturation: 1.0, contrast: 1.0, brightness: 0.0, gamma: 1.0 }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::with_emphasis(&SYSTEM_PALLETE)
+    }
+}
+
+impl Palette {
+    // Derives the emphasized colours from 64 base colours
+    fn with_emphasis(base: &[(u8, u8, u8)]) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            let mut channels = Vec::new();
+            if emphasis & 1 != 0 {
+                channels.push(Color::Red);
+            }
+            if emphasis & 2 != 0 {
+                channels.push(Color::Green);
+            }
+            if emphasis & 4 != 0 {
+                channels.push(Color::Blue);
+            }
+            colors.extend(base.iter().map(|rgb| emphasize(*rgb, &channels)));
+        }
+        Palette { colors }
+    }
+
+    // .pal files are plain RGB triplets: 64 colours (192 bytes), or all 512
+    // emphasis variants (1536 bytes)
+    pub fn from_bytes(data: &[u8]) -> Result<Palette, String> {
+        let colors: Vec<(u8, u8, u8)> = data.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect();
+        match data.len() {
+            192 => Ok(Palette::with_emphasis(&colors)),
+            1536 => Ok(Palette { colors }),
+            len => Err(format!("palette must be 192 or 1536 bytes, got {}", len)),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Palette::from_bytes(&data)
+    }
+
+    // The full 1536 byte form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.colors.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect()
+    }
+
+    // Decodes every colour from the composite signal the PPU generates for
+    // it, see ntsc
+    pub fn generate(settings: &PaletteSettings) -> Palette {
+        let colors = (0..512u16)
+            .map(|pixel| {
+                let (y, i, q) = ntsc::decode(pixel, settings.hue);
+                let y = (y - 0.5) * settings.contrast + 0.5 + settings.brightness;
+                let rgb = ntsc::yiq_to_rgb(y, i * settings.saturation, q * settings.saturation);
+                let channel = |value: f32| {
+                    let value = value.clamp(0.0, 1.0).powf(1.0 / settings.gamma);
+                    (value * 255.0).round() as u8
+                };
+                (channel(rgb[0]), channel(rgb[1]), channel(rgb[2]))
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    // Colour of a 9 bit PPU pixel
+    pub fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+        self.colors[(pixel & 0x1ff) as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_file_sizes() {
+        let base: Vec<u8> = (0..192).map(|i| i as u8).collect();
+        let palette = Palette::from_bytes(&base).unwrap();
+        assert_eq!(palette.rgb(0x01), (3, 4, 5));
+        assert_eq!(palette.rgb(0x01 | 0x40), emphasize((3, 4, 5), &[Color::Red]));
+
+        let full = palette.to_bytes();
+        assert_eq!(full.len(), 1536);
+        let reloaded = Palette::from_bytes(&full).unwrap();
+        assert_eq!(reloaded.rgb(0x1ff), palette.rgb(0x1ff));
+
+        assert!(Palette::from_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_generated_palette() {
+        let palette = Palette::generate(&PaletteSettings::default());
+        assert_eq!(palette.rgb(0x0f), (0, 0, 0));
+        assert_eq!(palette.rgb(0x30), (255, 255, 255));
+        let (r, g, b) = palette.rgb(0x16);
+        assert!(r > g && r > b);
+
+        // emphasis darkens
+        let sum = |(r, g, b): (u8, u8, u8)| r as u16 + g as u16 + b as u16;
+        assert!(sum(palette.rgb(0x20 | 0x40)) < sum(palette.rgb(0x20)));
+
+        let dark = Palette::generate(&PaletteSettings { brightness: -0.2, ..PaletteSettings::default() });
+        assert!(dark.rgb(0x10).0 < palette.rgb(0x10).0);
+    }
+}
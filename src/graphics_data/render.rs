@@ -0,0 +1,446 @@
+// Debug views of PPU memory, drawn straight from CHR, VRAM and the palette
+// rather than the way the PPU renders the picture.
+
+use super::image::Image;
+use crate::ppu::{MyPPU, PpuEvent};
+use std::fmt;
+
+pub const PATTERN_TABLES_WIDTH: usize = 256;
+pub const PATTERN_TABLES_HEIGHT: usize = 128;
+pub const NAMETABLES_WIDTH: usize = 512;
+pub const NAMETABLES_HEIGHT: usize = 480;
+
+pub const OAM_CELL_WIDTH: usize = 32;
+pub const OAM_CELL_HEIGHT: usize = 56;
+pub const OAM_SCALE: usize = 3;
+pub const PALETTE_SWATCH: usize = 24;
+pub const DOTS_PER_LINE: usize = 341;
+
+const SCROLL_WINDOW: (u8, u8, u8) = (0xff, 0x00, 0xff);
+const SPRITE_OUTLINE: (u8, u8, u8) = (0x00, 0xff, 0x00);
+const DROPPED_OUTLINE: (u8, u8, u8) = (0xff, 0x00, 0x00);
+const SELECTED_OUTLINE: (u8, u8, u8) = (0xff, 0xff, 0x00);
+
+// 2 bit colours of the 8x8 tile at `addr` in CHR
+pub fn decode_tile(chr: &[u8], addr: usize) -> [[u8; 8]; 8] {
+    let mut tile = [[0; 8]; 8];
+    for (y, row) in tile.iter_mut().enumerate() {
+        let mut upper = chr.get(addr + y).copied().unwrap_or(0);
+        let mut lower = chr.get(addr + y + 8).copied().unwrap_or(0);
+        for x in (0..8).rev() {
+            row[x] = (1 & lower) << 1 | (1 & upper);
+            upper >>= 1;
+            lower >>= 1;
+        }
+    }
+    tile
+}
+
+// System colour of `value` in palette `palette` (0-3 background, 4-7 sprites)
+pub fn palette_color(ppu: &MyPPU, palette: u8, value: u8) -> (u8, u8, u8) {
+    let entry = if value == 0 { 0 } else { palette as usize * 4 + value as usize };
+    ppu.palette.rgb((ppu.palette_table[entry] & 0x3f) as u16)
+}
+
+pub fn draw_tile(image: &mut Image, ppu: &MyPPU, chr_addr: usize, palette: u8, x: usize, y: usize) {
+    let tile = decode_tile(&ppu.chr_rom, chr_addr);
+    for (ty, row) in tile.iter().enumerate() {
+        for (tx, value) in row.iter().enumerate() {
+            image.set_pixel(x + tx, y + ty, palette_color(ppu, palette, *value));
+        }
+    }
+}
+
+// Both pattern tables side by side, 16x16 tiles each
+pub fn pattern_tables(ppu: &MyPPU, palette: u8) -> Image {
+    let mut image = Image::new(PATTERN_TABLES_WIDTH, PATTERN_TABLES_HEIGHT);
+    for table in 0..2 {
+        for tile in 0..256 {
+            let x = table * 128 + (tile % 16) * 8;
+            let y = (tile / 16) * 8;
+            draw_tile(&mut image, ppu, table * 0x1000 + tile * 16, palette, x, y);
+        }
+    }
+    image
+}
+
+pub fn pattern_tile_info(x: usize, y: usize) -> String {
+    let table = x / 128;
+    let tile = (y / 8) * 16 + (x % 128) / 8;
+    format!("pattern table {} tile ${:02x} at ${:04x}", table, tile, table * 0x1000 + tile * 16)
+}
+
+// Nametable, tile row and column under (x, y) of the nametables view
+fn nametable_position(x: usize, y: usize) -> (usize, usize, usize) {
+    let table = (y / 240) * 2 + x / 256;
+    (table, (y % 240) / 8, (x % 256) / 8)
+}
+
+fn attribute(ppu: &MyPPU, table: usize, row: usize, column: usize) -> (u16, u8, u8) {
+    let addr = 0x23c0 + table as u16 * 0x400 + (row / 4 * 8 + column / 4) as u16;
+    let value = ppu.vram[ppu.mirror_vram_addr(addr) as usize];
+    let shift = (row % 4 / 2) * 4 + (column % 4 / 2) * 2;
+    (addr, value, (value >> shift) & 0b11)
+}
+
+// All four logical nametables as the PPU sees them through the cart's
+// mirroring, with the scroll position outlined
+pub fn nametables(ppu: &MyPPU) -> Image {
+    let mut image = Image::new(NAMETABLES_WIDTH, NAMETABLES_HEIGHT);
+    let bank = ppu.control.bknd_pattern_addr() as usize;
+    for table in 0..4 {
+        for row in 0..30 {
+            for column in 0..32 {
+                let addr = 0x2000 + (table * 0x400 + row * 32 + column) as u16;
+                let tile = ppu.vram[ppu.mirror_vram_addr(addr) as usize] as usize;
+                let (_, _, palette) = attribute(ppu, table, row, column);
+                let x = (table % 2) * 256 + column * 8;
+                let y = (table / 2) * 240 + row * 8;
+                draw_tile(&mut image, ppu, bank + tile * 16, palette, x, y);
+            }
+        }
+    }
+
+    // t holds the top left corner of the screen
+    let t = ppu.loopy.t as usize;
+    let x = (t >> 10 & 1) * 256 + (t & 0x1f) * 8 + ppu.loopy.x as usize;
+    let y = (t >> 11 & 1) * 240 + (t >> 5 & 0x1f) * 8 + (t >> 12);
+    image.draw_rect(x, y, 256, 240, SCROLL_WINDOW);
+    image
+}
+
+pub fn nametable_tile_info(ppu: &MyPPU, x: usize, y: usize) -> String {
+    let (table, row, column) = nametable_position(x, y);
+    let addr = 0x2000 + (table * 0x400 + row * 32 + column) as u16;
+    let tile = ppu.vram[ppu.mirror_vram_addr(addr) as usize];
+    let (attr_addr, attr, palette) = attribute(ppu, table, row, column);
+    format!(
+        "${:04x} ({}, {}) tile ${:02x}, attribute ${:04x} = ${:02x}, palette {}",
+        addr, column, row, tile, attr_addr, attr, palette
+    )
+}
+
+// One OAM entry, decoded
+pub struct SpriteInfo {
+    pub index: usize,
+    pub x: u8,
+    // OAM Y, the sprite shows up one line below it
+    pub y: u8,
+    pub tile: u8,
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    // kept off at least one line by the 8 sprite limit last frame
+    pub dropped: bool,
+}
+
+impl fmt::Display for SpriteInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{:02} x:{:3} y:{:3} tile:${:02x} palette:{} {}{}{}{}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            self.palette,
+            if self.behind_background { "behind" } else { "front" },
+            if self.flip_horizontal { " hflip" } else { "" },
+            if self.flip_vertical { " vflip" } else { "" },
+            if self.dropped { " dropped" } else { "" },
+        )
+    }
+}
+
+pub fn sprites(ppu: &MyPPU) -> Vec<SpriteInfo> {
+    (0..64)
+        .map(|index| {
+            let entry = &ppu.oam_data[index * 4..index * 4 + 4];
+            SpriteInfo {
+                index,
+                y: entry[0],
+                tile: entry[1],
+                palette: entry[2] & 0b11,
+                behind_background: entry[2] & 0x20 != 0,
+                flip_horizontal: entry[2] & 0x40 != 0,
+                flip_vertical: entry[2] & 0x80 != 0,
+                x: entry[3],
+                dropped: ppu.dropped_sprites() & (1 << index) != 0,
+            }
+        })
+        .collect()
+}
+
+// The sprite as drawn, 8x8 or 8x16 depending on PPUCTRL
+pub fn sprite_image(ppu: &MyPPU, sprite: &SpriteInfo) -> Image {
+    let height = ppu.control.sprite_size() as usize;
+    let mut image = Image::new(8, height);
+    for half in 0..height / 8 {
+        let addr = if height == 16 {
+            (sprite.tile as usize & 1) * 0x1000 + (sprite.tile as usize & 0xfe) * 16 + half * 16
+        } else {
+            ppu.control.sprt_pattern_addr() as usize + sprite.tile as usize * 16
+        };
+        let tile = decode_tile(&ppu.chr_rom, addr);
+        for (ty, row) in tile.iter().enumerate() {
+            for (tx, value) in row.iter().enumerate() {
+                let x = if sprite.flip_horizontal { 7 - tx } else { tx };
+                let y = half * 8 + ty;
+                let y = if sprite.flip_vertical { height - 1 - y } else { y };
+                image.set_pixel(x, y, palette_color(ppu, 4 + sprite.palette, *value));
+            }
+        }
+    }
+    image
+}
+
+// The last frame with every sprite outlined: dropped ones in red, the
+// selected one in yellow
+pub fn sprite_overlay(ppu: &MyPPU, selected: Option<usize>) -> Image {
+    let mut image = Image {
+        width: 256,
+        height: 240,
+        data: ppu.frame.data.clone(),
+    };
+    let height = ppu.control.sprite_size() as usize;
+    for sprite in sprites(ppu).iter().rev() {
+        // Y of $EF and up is off screen
+        if sprite.y >= 0xef {
+            continue;
+        }
+        let color = match (Some(sprite.index) == selected, sprite.dropped) {
+            (true, _) => SELECTED_OUTLINE,
+            (false, true) => DROPPED_OUTLINE,
+            (false, false) => SPRITE_OUTLINE,
+        };
+        outline(&mut image, sprite.x as usize, sprite.y as usize + 1, 8, height, color);
+    }
+    image
+}
+
+// Rectangle outline that is clipped, not wrapped, at the screen edges
+fn outline(image: &mut Image, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+    for i in 0..width {
+        image.set_pixel(x + i, y, rgb);
+        image.set_pixel(x + i, y + height - 1, rgb);
+    }
+    for i in 0..height {
+        image.set_pixel(x, y + i, rgb);
+        image.set_pixel(x + width - 1, y + i, rgb);
+    }
+}
+
+// All 64 sprites blown up in an 8x8 grid, in OAM order
+pub fn oam_grid(ppu: &MyPPU, selected: Option<usize>) -> Image {
+    let mut image = Image::new(OAM_CELL_WIDTH * 8, OAM_CELL_HEIGHT * 8);
+    for sprite in sprites(ppu) {
+        let x = (sprite.index % 8) * OAM_CELL_WIDTH;
+        let y = (sprite.index / 8) * OAM_CELL_HEIGHT;
+        if Some(sprite.index) == selected {
+            image.fill_rect(x, y, OAM_CELL_WIDTH, OAM_CELL_HEIGHT, SELECTED_OUTLINE);
+        } else if sprite.dropped {
+            image.fill_rect(x, y, OAM_CELL_WIDTH, OAM_CELL_HEIGHT, DROPPED_OUTLINE);
+        }
+        image.blit(&sprite_image(ppu, &sprite), x + 4, y + 4, OAM_SCALE);
+    }
+    image
+}
+
+pub fn oam_cell_at(x: usize, y: usize) -> Option<usize> {
+    let index = (y / OAM_CELL_HEIGHT) * 8 + x / OAM_CELL_WIDTH;
+    if x < OAM_CELL_WIDTH * 8 && index < 64 {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+// Topmost sprite covering pixel (x, y) of the screen
+pub fn sprite_at(ppu: &MyPPU, x: usize, y: usize) -> Option<usize> {
+    let height = ppu.control.sprite_size() as usize;
+    sprites(ppu)
+        .iter()
+        .find(|s| {
+            x.wrapping_sub(s.x as usize) < 8 && y.wrapping_sub(s.y as usize + 1) < height && s.y < 0xef
+        })
+        .map(|s| s.index)
+}
+
+// The 32 palette RAM entries, background on top, sprites below
+pub fn palette_view(ppu: &MyPPU) -> Image {
+    let mut image = Image::new(PALETTE_SWATCH * 16, PALETTE_SWATCH * 2);
+    for entry in 0..32 {
+        let color = ppu.palette.rgb((ppu.palette_table[entry] & 0x3f) as u16);
+        let x = (entry % 16) * PALETTE_SWATCH;
+        let y = (entry / 16) * PALETTE_SWATCH;
+        image.fill_rect(x, y, PALETTE_SWATCH, PALETTE_SWATCH, color);
+    }
+    image
+}
+
+pub fn palette_entry_info(ppu: &MyPPU, entry: usize) -> String {
+    let value = ppu.palette_table[entry] & 0x3f;
+    let (r, g, b) = ppu.palette.rgb(value as u16);
+    format!("${:04x} = ${:02x} (#{:02x}{:02x}{:02x})", 0x3f00 + entry, value, r, g, b)
+}
+
+fn event_name(addr: u16) -> &'static str {
+    match addr {
+        0x2000..=0x3fff => match addr & 0x2007 {
+            0x2000 => "PPUCTRL",
+            0x2001 => "PPUMASK",
+            0x2002 => "PPUSTATUS",
+            0x2003 => "OAMADDR",
+            0x2004 => "OAMDATA",
+            0x2005 => "PPUSCROLL",
+            0x2006 => "PPUADDR",
+            _ => "PPUDATA",
+        },
+        0x4014 => "OAMDMA",
+        _ => "mapper",
+    }
+}
+
+fn event_color(addr: u16) -> (u8, u8, u8) {
+    match addr {
+        0x2000..=0x3fff => match addr & 0x2007 {
+            0x2000 => (0xff, 0x40, 0x40),
+            0x2001 => (0x40, 0xff, 0x40),
+            0x2002 => (0xff, 0x80, 0x00),
+            0x2003 | 0x2004 => (0xff, 0x40, 0xff),
+            0x2005 => (0x40, 0xff, 0xff),
+            0x2006 => (0xff, 0xff, 0x40),
+            _ => (0x40, 0x80, 0xff),
+        },
+        0x4014 => (0xff, 0xff, 0xff),
+        _ => (0xc0, 0x80, 0x40),
+    }
+}
+
+pub fn describe_event(event: &PpuEvent) -> String {
+    format!(
+        "${:04x} {} = ${:02x} at line {} dot {}",
+        event.addr,
+        event_name(event.addr),
+        event.value,
+        event.scanline,
+        event.dot
+    )
+}
+
+// One dot per pixel and one scanline per row for a whole frame, the
+// picture at its place in the visible area and every event marked where
+// it happened
+pub fn event_grid(ppu: &MyPPU) -> Image {
+    let lines = ppu.region().scanlines() as usize;
+    let mut image = Image::new(DOTS_PER_LINE, lines);
+    image.fill_rect(0, 0, DOTS_PER_LINE, lines, (0x20, 0x20, 0x20));
+    for y in 0..240 {
+        for x in 0..256 {
+            let base = (y * 256 + x) * 3;
+            let rgb = &ppu.frame.data[base..base + 3];
+            // dimmed so the markers stand out
+            image.set_pixel(x + 1, y, (rgb[0] / 2, rgb[1] / 2, rgb[2] / 2));
+        }
+    }
+    for event in ppu.events() {
+        let (x, y) = (event.dot, event.scanline as usize);
+        image.fill_rect(x.saturating_sub(1), y.saturating_sub(1), 3, 3, event_color(event.addr));
+    }
+    image
+}
+
+// The event closest to (dot, scanline), if there's one near
+pub fn event_at(ppu: &MyPPU, dot: usize, scanline: usize) -> Option<PpuEvent> {
+    let distance = |e: &&PpuEvent| {
+        let dx = e.dot as isize - dot as isize;
+        let dy = e.scanline as isize - scanline as isize;
+        dx * dx + dy * dy
+    };
+    ppu.events().iter().filter(|e| distance(e) <= 8).min_by_key(distance).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::Mirroring;
+
+    #[test]
+    fn test_decode_tile() {
+        let mut chr = vec![0; 16];
+        chr[0] = 0b1000_0001;
+        chr[8] = 0b1100_0000;
+        let tile = decode_tile(&chr, 0);
+        assert_eq!(tile[0], [3, 2, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(tile[1], [0; 8]);
+    }
+
+    #[test]
+    fn test_nametables_follow_mirroring() {
+        let mut chr = vec![0; 0x2000];
+        // tile 1 is solid colour 1
+        chr[16..24].fill(0xff);
+        let mut ppu = MyPPU::new(chr, Mirroring::VERTICAL);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu.vram[0] = 1;
+
+        let image = nametables(&ppu);
+        let white = ppu.palette.rgb(0x30);
+        assert_eq!(image.pixel(4, 4), white);
+        // $2800 mirrors $2000 with vertical mirroring, $2400 doesn't
+        assert_eq!(image.pixel(4, 244), white);
+        assert_ne!(image.pixel(260, 4), white);
+        // the scroll window sits at the origin
+        assert_eq!(image.pixel(100, 0), SCROLL_WINDOW);
+
+        assert!(nametable_tile_info(&ppu, 3, 243).starts_with("$2800 (0, 0) tile $01"));
+    }
+
+    #[test]
+    fn test_sprite_decoding() {
+        let mut chr = vec![0; 0x2000];
+        // tile 2: top row is colour 1 in the leftmost column only
+        chr[32] = 0x80;
+        let mut ppu = MyPPU::new(chr, Mirroring::VERTICAL);
+        ppu.palette_table[0x11] = 0x16;
+        ppu.oam_data[0..4].copy_from_slice(&[10, 2, 0b1100_0001, 20]);
+
+        let sprite = &sprites(&ppu)[0];
+        assert_eq!((sprite.x, sprite.y, sprite.tile, sprite.palette), (20, 10, 2, 1));
+        assert!(sprite.flip_horizontal && sprite.flip_vertical && !sprite.behind_background);
+        assert_eq!(sprite.to_string(), "#00 x: 20 y: 10 tile:$02 palette:1 front hflip vflip");
+
+        // both flips move the pixel to the opposite corner
+        ppu.palette_table[0x15] = 0x16;
+        let image = sprite_image(&ppu, sprite);
+        assert_eq!(image.pixel(7, 7), ppu.palette.rgb(0x16));
+        assert_eq!(image.pixel(0, 0), ppu.palette.rgb(ppu.palette_table[0] as u16));
+
+        assert_eq!(sprite_at(&ppu, 24, 15), Some(0));
+        assert_eq!(sprite_at(&ppu, 24, 10), None);
+        assert_eq!(oam_cell_at(OAM_CELL_WIDTH + 1, OAM_CELL_HEIGHT + 1), Some(9));
+        assert_eq!(palette_entry_info(&ppu, 0x15), "$3f15 = $16 (#ff2200)");
+    }
+
+    #[test]
+    fn test_event_grid() {
+        let mut ppu = MyPPU::new_empty_rom();
+        ppu.set_event_log(true);
+        for _ in 0..10 {
+            ppu.tick(1);
+        }
+        ppu.log_event(0x2005, 0x20);
+        while ppu.scanline() != 241 || ppu.cycle() < 2 {
+            ppu.tick(1);
+        }
+
+        let image = event_grid(&ppu);
+        assert_eq!((image.width, image.height), (341, 262));
+        assert_eq!(image.pixel(10, 0), event_color(0x2005));
+        let event = event_at(&ppu, 11, 2).unwrap();
+        assert_eq!(describe_event(&event), "$2005 PPUSCROLL = $20 at line 0 dot 10");
+        assert!(event_at(&ppu, 100, 100).is_none());
+    }
+}
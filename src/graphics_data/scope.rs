@@ -0,0 +1,277 @@
+// Debug view of the APU: an oscilloscope trace per channel next to what
+// its registers are set to, with periods turned into frequencies and
+// note names.
+
+use super::font::{self, CHAR_WIDTH, LINE_HEIGHT};
+use super::image::Image;
+use crate::apu::blip::Blip;
+use crate::apu::mixer::Channel;
+use crate::apu::Apu;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const CHANNELS: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+const TEXT_SCALE: usize = 2;
+const TEXT_WIDTH: usize = 26 * CHAR_WIDTH * TEXT_SCALE;
+const SCOPE_WIDTH: usize = 480;
+const ROW_HEIGHT: usize = 6 * LINE_HEIGHT * TEXT_SCALE + 4;
+pub const WIDTH: usize = TEXT_WIDTH + SCOPE_WIDTH;
+pub const HEIGHT: usize = ROW_HEIGHT * CHANNELS.len();
+
+// samples per second the traces are kept at, 16ms of it fits the width
+const SCOPE_RATE: f64 = 30000.0;
+const HISTORY: usize = SCOPE_WIDTH * 2;
+
+const COLORS: [(u8, u8, u8); 5] = [
+    (0xff, 0x60, 0x60),
+    (0xff, 0xc0, 0x40),
+    (0x60, 0xc0, 0xff),
+    (0xc0, 0xc0, 0xc0),
+    (0x80, 0xff, 0x80),
+];
+const TEXT: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const MUTED: (u8, u8, u8) = (0x60, 0x60, 0x60);
+const GRID: (u8, u8, u8) = (0x30, 0x30, 0x30);
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+// Nearest equal tempered note and how many cents off it `frequency` is
+pub fn note_name(frequency: f32) -> String {
+    if !frequency.is_finite() || frequency <= 0.0 {
+        return "-".to_string();
+    }
+    let midi = 69.0 + 12.0 * (frequency / 440.0).log2();
+    let note = midi.round() as i32;
+    let cents = ((midi - note as f32) * 100.0).round() as i32;
+    let name = NOTE_NAMES[note.rem_euclid(12) as usize];
+    format!("{}{} {:+}", name, note.div_euclid(12) - 1, cents)
+}
+
+fn envelope_info(constant: bool, period: u8, looping: bool) -> String {
+    match (constant, looping) {
+        (true, _) => "CONST".to_string(),
+        (false, true) => format!("P{} LOOP", period),
+        (false, false) => format!("P{}", period),
+    }
+}
+
+// Register state of `channel`, a few short lines
+pub fn channel_info(apu: &Apu, channel: Channel) -> Vec<String> {
+    let clock = apu.region().cpu_clock_hz() as f32;
+    let halt = |halt: bool| if halt { " HALT" } else { "" };
+    let mut lines = vec![match channel {
+        Channel::Pulse1 => "PULSE 1".to_string(),
+        Channel::Pulse2 => "PULSE 2".to_string(),
+        _ => channel.name().to_ascii_uppercase(),
+    }];
+    if apu.mixer.muted(channel) {
+        lines[0] += " MUTED";
+    }
+
+    match channel {
+        Channel::Pulse1 | Channel::Pulse2 => {
+            let pulse = if channel == Channel::Pulse1 { &apu.pulse1 } else { &apu.pulse2 };
+            let frequency = clock / (16.0 * (pulse.period as f32 + 1.0));
+            lines.push(format!("DUTY {}% VOL {}", [12, 25, 50, 75][pulse.duty as usize], pulse.envelope.volume()));
+            lines.push(format!("PER ${:03X} {:.1}HZ", pulse.period, frequency));
+            lines.push(format!("NOTE {}", note_name(frequency)));
+            let envelope = envelope_info(pulse.envelope.constant, pulse.envelope.period, pulse.envelope.looping);
+            lines.push(format!("LEN {}{} ENV {}", pulse.length.counter, halt(pulse.length.halt), envelope));
+            let sweep = &pulse.sweep;
+            lines.push(if sweep.enabled {
+                format!("SWEEP P{} {}{}", sweep.period, if sweep.negate { "-" } else { "+" }, sweep.shift)
+            } else {
+                "SWEEP OFF".to_string()
+            });
+        }
+        Channel::Triangle => {
+            let triangle = &apu.triangle;
+            let frequency = clock / (32.0 * (triangle.period as f32 + 1.0));
+            lines.push(format!("PER ${:03X} {:.1}HZ", triangle.period, frequency));
+            lines.push(format!("NOTE {}", note_name(frequency)));
+            lines.push(format!("LEN {}{}", triangle.length.counter, halt(triangle.length.halt)));
+            lines.push(format!("LIN {} RELOAD {}", triangle.linear_counter, triangle.linear_reload_value));
+            lines.push(format!("STEP {}", triangle.step));
+        }
+        Channel::Noise => {
+            let noise = &apu.noise;
+            lines.push(format!("MODE {}", if noise.short_mode { "SHORT" } else { "LONG" }));
+            lines.push(format!("PER {} = {} CYCLES", noise.period_index, noise.period(apu.region())));
+            lines.push(format!("VOL {}", noise.envelope.volume()));
+            let envelope = envelope_info(noise.envelope.constant, noise.envelope.period, noise.envelope.looping);
+            lines.push(format!("LEN {}{} ENV {}", noise.length.counter, halt(noise.length.halt), envelope));
+        }
+        Channel::Dmc => {
+            let dmc = &apu.dmc;
+            lines.push(format!("ADDR ${:04X}", dmc.sample_address));
+            lines.push(format!("LEN {} BYTES", dmc.sample_length));
+            lines.push(format!("AT ${:04X} {} LEFT", dmc.current_address, dmc.bytes_remaining));
+            lines.push(format!("RATE {} {:.0}HZ", dmc.rate_index, clock / dmc.rate(apu.region()) as f32));
+            let mut flags = format!("LEVEL {}", dmc.level);
+            if dmc.irq_enabled {
+                flags += " IRQ";
+            }
+            if dmc.looping {
+                flags += " LOOP";
+            }
+            lines.push(flags);
+        }
+        Channel::Expansion => {}
+    }
+    lines
+}
+
+// Keeps the last few milliseconds of every channel for drawing
+pub struct Scope {
+    blips: Vec<Rc<RefCell<Blip>>>,
+    history: Vec<Vec<f32>>,
+    samples: Vec<f32>,
+}
+
+// Where a rising edge crosses the middle of the trace in the older half of
+// `history`, so a steady tone stands still instead of scrolling
+fn trigger(history: &[f32]) -> usize {
+    let start = history.len().saturating_sub(HISTORY);
+    let history = &history[start..];
+    let searchable = history.len().saturating_sub(SCOPE_WIDTH);
+    let (min, max) = history.iter().fold((f32::MAX, f32::MIN), |(min, max), s| (min.min(*s), max.max(*s)));
+    let middle = (min + max) / 2.0;
+    (1..searchable)
+        .find(|i| history[i - 1] < middle && history[*i] >= middle)
+        .unwrap_or(searchable)
+        + start
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Scope {
+            blips: CHANNELS
+                .iter()
+                .map(|_| Rc::new(RefCell::new(Blip::new(1.0, SCOPE_RATE))))
+                .collect(),
+            history: vec![vec![0.0; HISTORY]; CHANNELS.len()],
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn attach(&self, apu: &mut Apu) {
+        for (channel, blip) in CHANNELS.iter().zip(self.blips.iter()) {
+            apu.attach_channel_blip(Some(*channel), blip.clone());
+        }
+    }
+
+    pub fn detach(&self, apu: &mut Apu) {
+        for blip in self.blips.iter() {
+            apu.detach_blip(blip);
+        }
+    }
+
+    // Takes in what the APU produced since the last call
+    pub fn update(&mut self) {
+        for (blip, history) in self.blips.iter().zip(self.history.iter_mut()) {
+            self.samples.clear();
+            blip.borrow_mut().read_samples(&mut self.samples);
+            history.extend_from_slice(&self.samples);
+            let excess = history.len().saturating_sub(HISTORY);
+            history.drain(..excess);
+        }
+    }
+
+    pub fn draw(&self, apu: &Apu) -> Image {
+        let mut image = Image::new(WIDTH, HEIGHT);
+        for (row, channel) in CHANNELS.iter().enumerate() {
+            let top = row * ROW_HEIGHT;
+            let muted = apu.mixer.muted(*channel);
+            let color = if muted { MUTED } else { COLORS[row] };
+
+            for (line, text) in channel_info(apu, *channel).iter().enumerate() {
+                let text_color = if line == 0 { color } else { TEXT };
+                font::draw_text(&mut image, 4, top + 4 + line * LINE_HEIGHT * TEXT_SCALE, text, TEXT_SCALE, text_color);
+            }
+
+            image.fill_rect(TEXT_WIDTH, top + ROW_HEIGHT / 2, SCOPE_WIDTH, 1, GRID);
+            image.fill_rect(0, top + ROW_HEIGHT - 1, WIDTH, 1, GRID);
+
+            // the loudest the channel can get on its own fills the row
+            let full = apu.mixer.mix_channel(*channel, [15, 15, 15, 15, 127], 0.0);
+            let history = &self.history[row];
+            let start = trigger(history);
+            let y_of = |sample: f32| {
+                let level = (sample / full).clamp(-0.1, 1.1);
+                (top as f32 + 4.0 + (1.0 - level) / 1.2 * (ROW_HEIGHT - 8) as f32) as usize
+            };
+            let mut last = y_of(history[start]);
+            for x in 0..SCOPE_WIDTH {
+                let y = y_of(history.get(start + x).copied().unwrap_or(0.0));
+                let (from, to) = if y < last { (y, last) } else { (last, y) };
+                image.fill_rect(TEXT_WIDTH + x, from, 1, to - from + 1, color);
+                last = y;
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::Region;
+
+    #[test]
+    fn test_note_name() {
+        assert_eq!(note_name(440.0), "A4 +0");
+        assert_eq!(note_name(261.63), "C4 +0");
+        assert_eq!(note_name(450.0), "A4 +39");
+        assert_eq!(note_name(0.0), "-");
+    }
+
+    #[test]
+    fn test_channel_info() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x1f);
+        // 50% duty, constant volume 15, period $0FD: about 440Hz
+        apu.write_register(0x4000, 0xbf);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0x00);
+        apu.write_register(0x4012, 0x02);
+        apu.mixer.set_muted(Channel::Noise, true);
+
+        let pulse = channel_info(&apu, Channel::Pulse1);
+        assert_eq!(pulse[1], "DUTY 50% VOL 15");
+        assert_eq!(pulse[2], "PER $0FD 440.4HZ");
+        assert_eq!(pulse[3], "NOTE A4 +2");
+        assert_eq!(pulse[4], "LEN 10 HALT ENV CONST");
+        assert_eq!(channel_info(&apu, Channel::Noise)[0], "NOISE MUTED");
+        assert_eq!(channel_info(&apu, Channel::Dmc)[1], "ADDR $C080");
+    }
+
+    #[test]
+    fn test_scope_trace() {
+        let mut apu = Apu::new(Region::Ntsc);
+        let mut scope = Scope::new();
+        scope.attach(&mut apu);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0xbf);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0x00);
+        for _ in 0..29830 {
+            apu.tick();
+        }
+        scope.update();
+
+        let image = scope.draw(&apu);
+        assert_eq!((image.width, image.height), (WIDTH, HEIGHT));
+        // the square wave reaches both the top and the bottom of its row
+        let column = |x: usize| (0..ROW_HEIGHT).filter(|y| image.pixel(x, *y) == COLORS[0]).collect::<Vec<_>>();
+        let rows: Vec<usize> = (TEXT_WIDTH..WIDTH).flat_map(column).collect();
+        assert!(rows.iter().any(|y| *y < ROW_HEIGHT / 4));
+        assert!(rows.iter().any(|y| *y > ROW_HEIGHT * 3 / 4));
+    }
+}
@@ -4,26 +4,50 @@ pub mod cpu;
 pub mod opcodes;
 pub mod trace;
 pub mod ppu;
+pub mod apu;
+pub mod audio;
 pub mod graphics_data;
 pub mod controller;
+pub mod savestate;
+pub mod cdl;
+pub mod debugger;
+pub mod region;
+pub mod viewer;
 
+use apu::mixer::Channel;
+use apu::wav::WavRecorder;
+use apu::Apu;
+use audio::{AudioOutput, AudioSettings};
 use bus::Bus;
 use cart::Rom;
 use cpu::Mem;
 use cpu::CPU;
-use trace::trace;
-use graphics_data::frame::Frame;
+use debugger::Debugger;
+use debugger::gdb::GdbStub;
+use debugger::history::History;
+use cdl::CodeDataLogger;
+use trace::TraceFormat;
+use trace::TraceLogger;
 use graphics_data::palette;
+use graphics_data::ntsc::{self, NtscFilter, NtscSettings};
+use graphics_data::palette::{Palette, PaletteSettings};
 use ppu::MyPPU;
+use region::{Region, RomDatabase};
+use viewer::{AudioViewer, EventViewer, PpuViewer, SpriteViewer};
 // use rand::Rng;
 
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
 
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate lazy_static;
@@ -99,8 +123,257 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
     }
 }
 
+fn save_cdl(cdl: &Option<(String, Rc<RefCell<CodeDataLogger>>)>) {
+    if let Some((path, logger)) = cdl {
+        let logger = logger.borrow();
+        logger.save(path).unwrap();
+        let (code, data, drawn) = logger.coverage();
+        println!("cdl: {} code, {} data, {} chr bytes logged to {}", code, data, drawn, path);
+    }
+}
+
+// Flushes a file trace, or writes a ring buffer trace out to `path`
+fn finish_trace(trace: Option<TraceLogger>, path: Option<&str>) {
+    if let (Some(mut trace), Some(path)) = (trace, path) {
+        match trace.dump(path) {
+            Ok(()) => println!("trace written to {}", path),
+            Err(e) => eprintln!("failed to write trace to {}: {}", path, e),
+        }
+    }
+}
+
+// Display options shared by windowed and headless runs: --palette*,
+// --no-sprite-limit, and --hide-background, --hide-sprites and
+// --hide-sprite=<n>[,<n>...] to leave layers or single OAM entries out of
+// the picture
+fn configure_ppu(ppu: &mut MyPPU, args: &[String], palette: Option<Palette>) {
+    if let Some(palette) = palette {
+        ppu.palette = palette;
+    }
+    if args.iter().any(|arg| arg == "--no-sprite-limit") {
+        ppu.sprite_limit = false;
+    }
+    ppu.show_background = !args.iter().any(|arg| arg == "--hide-background");
+    ppu.show_sprites = !args.iter().any(|arg| arg == "--hide-sprites");
+    for list in args.iter().filter_map(|arg| arg.strip_prefix("--hide-sprite=")) {
+        for index in list.split(',') {
+            let index = index.parse::<u8>().ok().filter(|i| *i < 64).expect("invalid sprite index");
+            ppu.hidden_sprites |= 1 << index;
+        }
+    }
+}
+
+// Mixer options: --mute=<channel>[,<channel>...], --solo=<channel> and
+// --channel-volume=<channel>:<0-100>[,...], channels being pulse1, pulse2,
+// triangle, noise, dmc and expansion
+fn configure_apu(apu: &mut Apu, args: &[String]) {
+    for list in args.iter().filter_map(|arg| arg.strip_prefix("--mute=")) {
+        for name in list.split(',') {
+            apu.mixer.set_muted(Channel::parse(name).unwrap(), true);
+        }
+    }
+    if let Some(name) = args.iter().find_map(|arg| arg.strip_prefix("--solo=")) {
+        apu.mixer.solo(Channel::parse(name).unwrap());
+    }
+    for list in args.iter().filter_map(|arg| arg.strip_prefix("--channel-volume=")) {
+        for setting in list.split(',') {
+            let (name, volume) = setting.split_once(':').expect("expected <channel>:<volume>");
+            let volume = volume.parse::<f32>().expect("invalid channel volume");
+            apu.mixer.set_volume(Channel::parse(name).unwrap(), volume / 100.0);
+        }
+    }
+}
+
+// --record-wav=<path> records the audio from the start, --wav-stems adds
+// one file per channel; F9 starts and stops recording while playing
+struct RecordingOptions {
+    path: Option<String>,
+    stems: bool,
+    sample_rate: u32,
+}
+
+impl RecordingOptions {
+    fn from_args(args: &[String], sample_rate: u32) -> Self {
+        RecordingOptions {
+            path: args.iter().find_map(|arg| arg.strip_prefix("--record-wav=")).map(|path| path.to_string()),
+            stems: args.iter().any(|arg| arg == "--wav-stems"),
+            sample_rate,
+        }
+    }
+
+    // Starts recording if `recorder` is idle, stops it otherwise
+    fn toggle(&self, apu: &mut Apu, recorder: &mut Option<WavRecorder>) {
+        if let Some(recording) = recorder.take() {
+            let paths = recording.paths().join(", ");
+            match recording.stop(apu) {
+                Ok(()) => println!("audio recorded to {}", paths),
+                Err(e) => eprintln!("failed to finish recording: {}", e),
+            }
+            return;
+        }
+        let path = self.path.clone().unwrap_or_else(|| {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+            format!("recording-{}.wav", now.as_secs())
+        });
+        match WavRecorder::start(apu, &path, self.stems, self.sample_rate) {
+            Ok(recording) => {
+                println!("recording audio to {}", path);
+                *recorder = Some(recording);
+            }
+            Err(e) => eprintln!("failed to start recording: {}", e),
+        }
+    }
+}
+
+fn update_recording(apu: &mut Apu, recorder: &mut Option<WavRecorder>) {
+    if let Some(Err(e)) = recorder.as_mut().map(|recording| recording.update()) {
+        eprintln!("recording stopped: {}", e);
+        // still detach from the APU and fix up what headers we can
+        if let Err(e) = recorder.take().unwrap().stop(apu) {
+            eprintln!("failed to finish recording: {}", e);
+        }
+    }
+}
+
+// Runs `frames` frames as fast as possible with no window, then dumps the
+// PPU views to `dir` if there is one. Audio is recorded with --record-wav.
+fn run_headless(
+    rom: Rom,
+    region: Region,
+    args: &[String],
+    palette: Option<Palette>,
+    frames: usize,
+    dir: Option<&str>,
+    recording: RecordingOptions,
+) {
+    let frame_count = Rc::new(Cell::new(0));
+    let counter = frame_count.clone();
+    let mut bus = Bus::new(rom, move |_: &MyPPU, _: &mut controller::Joypad| {
+        counter.set(counter.get() + 1);
+    });
+    bus.set_region(region);
+    bus.ppu_mut().set_event_log(dir.is_some());
+    configure_ppu(bus.ppu_mut(), args, palette);
+    configure_apu(bus.apu_mut(), args);
+    let mut recorder = None;
+    if recording.path.is_some() {
+        recording.toggle(bus.apu_mut(), &mut recorder);
+    }
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    let mut last_frame = 0;
+    while frame_count.get() < frames {
+        cpu.step();
+        if frame_count.get() != last_frame {
+            last_frame = frame_count.get();
+            update_recording(cpu.bus.apu_mut(), &mut recorder);
+        }
+    }
+    if recorder.is_some() {
+        recording.toggle(cpu.bus.apu_mut(), &mut recorder);
+    }
+    if let Some(dir) = dir {
+        match viewer::export_pngs(cpu.bus.ppu(), dir) {
+            Ok(()) => println!("PPU views written to {}", dir),
+            Err(e) => eprintln!("failed to export PPU views: {}", e),
+        }
+    }
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let debug = args.iter().any(|arg| arg == "--debug");
+    let gdb_port = args.iter().find_map(|arg| match arg.as_str() {
+        "--gdb" => Some(1234),
+        _ => arg.strip_prefix("--gdb=").map(|port| port.parse::<u16>().expect("invalid gdb port")),
+    });
+    let rom_path = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .map(|arg| arg.as_str())
+        .unwrap_or("Pac-Man (USA) (Tengen).nes");
+    let cdl_path = args.iter().find_map(|arg| arg.strip_prefix("--cdl="));
+    // --trace=<path> logs straight to the file; together with
+    // --trace-ring=<n> only the last n instructions are kept and written to
+    // the file on exit or crash
+    let trace_path = args.iter().find_map(|arg| arg.strip_prefix("--trace="));
+    let trace_ring = args.iter().find_map(|arg| {
+        arg.strip_prefix("--trace-ring=")
+            .map(|n| n.parse::<usize>().expect("invalid trace ring size"))
+    });
+    let float_arg = |name: &str, default: f32| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(name))
+            .map(|value| value.parse::<f32>().unwrap_or_else(|_| panic!("invalid value for {}", name)))
+            .unwrap_or(default)
+    };
+    // --ntsc runs the picture through the composite video filter,
+    // --ntsc-sharpness=, --ntsc-hue= and --ntsc-saturation= tune it
+    let defaults = NtscSettings::default();
+    let ntsc_filter = if args.iter().any(|arg| arg == "--ntsc") {
+        Some(NtscFilter::new(NtscSettings {
+            sharpness: float_arg("--ntsc-sharpness=", defaults.sharpness),
+            hue: float_arg("--ntsc-hue=", defaults.hue),
+            saturation: float_arg("--ntsc-saturation=", defaults.saturation),
+        }))
+    } else {
+        None
+    };
+    // --palette=<file.pal> loads a palette, --palette-generate builds one
+    // from --palette-hue=, -saturation=, -contrast=, -brightness= and -gamma=
+    let defaults = PaletteSettings::default();
+    let palette = if let Some(path) = args.iter().find_map(|arg| arg.strip_prefix("--palette=")) {
+        Some(Palette::load(path).unwrap())
+    } else if args.iter().any(|arg| arg == "--palette-generate") {
+        Some(Palette::generate(&PaletteSettings {
+            hue: float_arg("--palette-hue=", defaults.hue),
+            saturation: float_arg("--palette-saturation=", defaults.saturation),
+            contrast: float_arg("--palette-contrast=", defaults.contrast),
+            brightness: float_arg("--palette-brightness=", defaults.brightness),
+            gamma: float_arg("--palette-gamma=", defaults.gamma),
+        }))
+    } else {
+        None
+    };
+    let region_arg = args.iter().find_map(|arg| {
+        arg.strip_prefix("--region=").map(|name| Region::parse(name).unwrap())
+    });
+
+    //load the game
+    let bytes: Vec<u8> = std::fs::read(rom_path).unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+
+    // --region=ntsc|pal|dendy, then the NES 2.0 header, then the ROM
+    // database given with --region-db=<path>, and only then the region tag
+    // in the file name
+    let database = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--region-db="))
+        .map(|path| RomDatabase::load(path).unwrap())
+        .unwrap_or_default();
+    let region = region_arg
+        .or_else(|| Region::detect(&rom, &database, rom_path))
+        .unwrap_or_default();
+    let frame_time = Duration::from_secs_f64(1.0 / region.frame_rate());
+    let mut next_frame = Instant::now();
+
+    let recording = RecordingOptions::from_args(&args, float_arg("--sample-rate=", AudioSettings::default().sample_rate as f32) as u32);
+
+    // --headless runs --frames=<n> frames (60 by default) without a window,
+    // --export-viewers=<dir> does the same and then writes the picture and
+    // PPU views to dir
+    let export_dir = args.iter().find_map(|arg| arg.strip_prefix("--export-viewers="));
+    if export_dir.is_some() || args.iter().any(|arg| arg == "--headless") {
+        let frames = args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("--frames="))
+            .map(|n| n.parse::<usize>().expect("invalid frame count"))
+            .unwrap_or(60);
+        run_headless(rom, region, &args, palette, frames, export_dir, recording);
+        return;
+    }
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -110,20 +383,95 @@ fn main() {
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // frames are paced to the console's own rate below, not the monitor's
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
+    // --no-audio, --volume=<0-100>, --audio-latency=<ms> and
+    // --sample-rate=<hz>; without a sound device the game just runs silent
+    let defaults = AudioSettings::default();
+    let mut audio = if args.iter().any(|arg| arg == "--no-audio") {
+        None
+    } else {
+        let settings = AudioSettings {
+            sample_rate: float_arg("--sample-rate=", defaults.sample_rate as f32) as u32,
+            latency_ms: float_arg("--audio-latency=", defaults.latency_ms as f32) as u32,
+            volume: float_arg("--volume=", defaults.volume * 100.0).clamp(0.0, 100.0) / 100.0,
+        };
+        match sdl_context.audio().and_then(|subsystem| AudioOutput::new(&subsystem, settings)) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                eprintln!("audio disabled: {}", e);
+                None
+            }
+        }
+    };
+    let blip = audio.as_ref().map(|output| output.blip());
+    // --viewer opens the pattern table and nametable viewer
+    let mut viewer = if args.iter().any(|arg| arg == "--viewer") {
+        Some(PpuViewer::new(&video_subsystem).unwrap())
+    } else {
+        None
+    };
+    // --sprite-viewer opens the OAM inspector and palette viewer
+    let mut sprite_viewer = if args.iter().any(|arg| arg == "--sprite-viewer") {
+        Some(SpriteViewer::new(&video_subsystem).unwrap())
+    } else {
+        None
+    };
+    // --event-viewer opens the PPU event viewer
+    let mut event_viewer = if args.iter().any(|arg| arg == "--event-viewer") {
+        Some(EventViewer::new(&video_subsystem, region).unwrap())
+    } else {
+        None
+    };
+    // --audio-viewer opens the APU oscilloscope and channel state panel
+    let audio_viewer = Rc::new(RefCell::new(if args.iter().any(|arg| arg == "--audio-viewer") {
+        Some(AudioViewer::new(&video_subsystem).unwrap())
+    } else {
+        None
+    }));
+    let gameloop_audio_viewer = audio_viewer.clone();
+    let log_events = event_viewer.is_some();
     canvas.set_scale(3.0, 3.0).unwrap();
 
     let creator = canvas.texture_creator();
+    let texture_width = if ntsc_filter.is_some() { ntsc::OUTPUT_WIDTH } else { 256 };
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .create_texture_target(PixelFormatEnum::RGB24, texture_width as u32, 240)
         .unwrap();
+    let mut ntsc_output = vec![0; ntsc::OUTPUT_WIDTH * 240 * 3];
 
-    //load the game
-    let bytes: Vec<u8> = std::fs::read("Pac-Man (USA) (Tengen).nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
 
-    let mut frame = Frame::new();
+    // keep logging on top of an earlier session for the same ROM
+    let cdl = cdl_path.map(|path| {
+        let logger = match std::fs::read(path) {
+            Ok(data) => CodeDataLogger::from_bytes(&data, rom.prg_rom.len(), rom.chr_rom.len()).unwrap(),
+            Err(_) => CodeDataLogger::new(rom.prg_rom.len(), rom.chr_rom.len()),
+        };
+        (path.to_string(), Rc::new(RefCell::new(logger)))
+    });
+
+    let mut trace = match (trace_ring, trace_path) {
+        (Some(capacity), _) => Some(TraceLogger::ring_buffer(capacity)),
+        (None, Some(path)) => Some(TraceLogger::to_file(path).unwrap()),
+        (None, None) => None,
+    };
+    if let Some(trace) = trace.as_mut() {
+        if args.iter().any(|arg| arg == "--trace-format=mesen") {
+            trace.format = TraceFormat::Mesen;
+        }
+        trace.show_ppu = args.iter().any(|arg| arg == "--trace-ppu");
+        trace.show_cycles = args.iter().any(|arg| arg == "--trace-cycles");
+    }
+
+    let quit = Rc::new(Cell::new(false));
+    let gameloop_quit = quit.clone();
+    let mut recorder = None;
+    // the hotkey is seen by the gameloop, which can't reach the APU
+    let toggle_recording = Rc::new(Cell::new(recording.path.is_some()));
+    let gameloop_toggle_recording = toggle_recording.clone();
+    let frame_done = Rc::new(Cell::new(false));
+    let gameloop_frame_done = frame_done.clone();
 
     // run the game cycle
     let mut key_map = HashMap::new();
@@ -138,20 +486,79 @@ fn main() {
 
 
    // run the game cycle
-   let bus = Bus::new(rom, move |ppu: &MyPPU, joypad: &mut controller::Joypad| {
-       graphics_data::render(ppu, &mut frame);
-       texture.update(None, &frame.data, 256 * 3).unwrap();
+   let mut bus = Bus::new(rom, move |ppu: &MyPPU, joypad: &mut controller::Joypad| {
+       if let Some(filter) = ntsc_filter.as_ref() {
+           filter.apply(&ppu.frame, &mut ntsc_output);
+           texture.update(None, &ntsc_output, ntsc::OUTPUT_WIDTH * 3).unwrap();
+       } else {
+           texture.update(None, &ppu.frame.data, 256 * 3).unwrap();
+       }
 
        canvas.copy(&texture, None, None).unwrap();
 
        canvas.present();
+       if let Some(viewer) = viewer.as_mut() {
+           viewer.update(ppu).unwrap();
+       }
+       if let Some(viewer) = sprite_viewer.as_mut() {
+           viewer.update(ppu).unwrap();
+       }
+       if let Some(viewer) = event_viewer.as_mut() {
+           viewer.update(ppu).unwrap();
+       }
+
+       next_frame += frame_time;
+       let now = Instant::now();
+       if next_frame > now {
+           std::thread::sleep(next_frame - now);
+       } else {
+           // running behind (debugger, slow host); don't try to catch up
+           next_frame = now;
+       }
+       if let Some(audio) = audio.as_mut() {
+           audio.update();
+       }
+       gameloop_frame_done.set(true);
+
        for event in event_pump.poll_iter() {
+           if viewer.as_mut().is_some_and(|viewer| viewer.handle_event(&event))
+               || sprite_viewer.as_mut().is_some_and(|viewer| viewer.handle_event(&event))
+           {
+               continue;
+           }
+           if let Some(viewer) = event_viewer.as_mut() {
+               if let Some((dot, scanline)) = viewer.handle_event(&event) {
+                   viewer.select(ppu, dot, scanline);
+                   continue;
+               }
+           }
            match event {
                Event::Quit { .. }
                | Event::KeyDown {
                    keycode: Some(Keycode::Escape),
                    ..
-               } => std::process::exit(0),
+               } => gameloop_quit.set(true),
+
+               Event::KeyDown { keycode: Some(Keycode::F9), .. } => gameloop_toggle_recording.set(true),
+
+               // with more than one window open, closing one doesn't quit
+               Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                   if viewer.as_ref().is_some_and(|viewer| viewer.window_id() == window_id) {
+                       viewer = None;
+                   } else if sprite_viewer.as_ref().is_some_and(|viewer| viewer.window_id() == window_id) {
+                       sprite_viewer = None;
+                   } else if event_viewer.as_ref().is_some_and(|viewer| viewer.window_id() == window_id) {
+                       event_viewer = None;
+                   } else if let Some(viewer) = gameloop_audio_viewer
+                       .borrow_mut()
+                       .as_mut()
+                       .filter(|viewer| viewer.window_id() == window_id)
+                   {
+                       viewer.close();
+                   } else {
+                       gameloop_quit.set(true);
+                   }
+               }
 
 
                Event::KeyDown { keycode, .. } => {
@@ -170,8 +577,84 @@ fn main() {
        }
    });
 
+    bus.set_region(region);
+    bus.ppu_mut().set_event_log(log_events);
+    if let Some((_, logger)) = cdl.as_ref() {
+        bus.attach_cdl(logger.clone());
+    }
+    configure_ppu(bus.ppu_mut(), &args, palette);
+    configure_apu(bus.apu_mut(), &args);
+    if let Some(viewer) = audio_viewer.borrow().as_ref() {
+        viewer.attach(bus.apu_mut());
+    }
+    if let Some(blip) = blip {
+        bus.apu_mut().attach_blip(blip);
+    }
+
     let mut cpu = CPU::new(bus);
 
     cpu.reset();
-    cpu.run();
-}  
\ No newline at end of file
+
+    let mut stub = gdb_port.map(|port| {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        GdbStub::accept(&listener, &mut cpu).unwrap()
+    });
+    let mut debugger = if debug && stub.is_none() {
+        let mut debugger = Debugger::new();
+        debugger.attach(&mut cpu);
+        debugger.history = Some(History::new());
+        debugger.trace = trace.take();
+        debugger.pause();
+        Some(debugger)
+    } else {
+        None
+    };
+
+    // a crash still leaves the trace and CDL behind
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while !quit.get() {
+            if toggle_recording.take() {
+                recording.toggle(cpu.bus.apu_mut(), &mut recorder);
+            }
+            if frame_done.take() {
+                update_recording(cpu.bus.apu_mut(), &mut recorder);
+                let mut slot = audio_viewer.borrow_mut();
+                if slot.as_ref().is_some_and(|viewer| viewer.closed()) {
+                    slot.take().unwrap().detach(cpu.bus.apu_mut());
+                } else if let Some(viewer) = slot.as_mut() {
+                    viewer.update(cpu.bus.apu()).unwrap();
+                }
+            }
+            cpu.step_with_callback(|cpu| {
+                if let Some(stub) = stub.as_mut() {
+                    if let Err(e) = stub.on_instruction(cpu) {
+                        eprintln!("gdb connection lost, detaching: {}", e);
+                        stub.detach();
+                    }
+                    if stub.is_killed() {
+                        quit.set(true);
+                    }
+                } else if let Some(debugger) = debugger.as_mut() {
+                    debugger::repl::on_instruction(debugger, cpu);
+                    if debugger.quit {
+                        quit.set(true);
+                    }
+                } else if let Some(trace) = trace.as_mut() {
+                    trace.log(cpu);
+                }
+            });
+        }
+    }));
+
+    save_cdl(&cdl);
+    let trace = debugger.and_then(|debugger| debugger.trace).or(trace);
+    finish_trace(trace, trace_path);
+    if recorder.is_some() {
+        recording.toggle(cpu.bus.apu_mut(), &mut recorder);
+    }
+
+    if let Err(panic) = result {
+        panic::resume_unwind(panic);
+    }
+}
@@ -1,35 +1,97 @@
 
 use crate::cart::Mirroring;
+use crate::bus::{AccessKind, MemAccess};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::cdl::CodeDataLogger;
+use crate::graphics_data::frame::Frame;
+use crate::graphics_data::palette::Palette;
+use crate::region::Region;
+use std::cell::RefCell;
+use std::rc::Rc;
 use ppu_registers::ppu_ctrl::ControlRegister;
 use ppu_registers::ppu_mask::MaskRegister;
 use ppu_registers::ppu_status::StatusRegister;
-use ppu_registers::ppu_scroll::ScrollRegister;
-use ppu_registers::ppu_addr::AddrRegister;
+use ppu_registers::ppu_loopy::LoopyRegisters;
 
 pub mod ppu_registers;
-
+pub mod render;
+
+use render::{Background, LineSprite};
+
+// Bits of the I/O latch fade back to 0 roughly 600ms after they were last
+// driven, counted here in PPU dots.
+const OPEN_BUS_DECAY_DOTS: u64 = 341 * 262 * 36;
+
+// A CPU write to a PPU register, OAM DMA or a mapper register, stamped with
+// where the PPU was when it happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpuEvent {
+    pub addr: u16,
+    pub value: u8,
+    pub scanline: u16,
+    pub dot: usize,
+}
 
 pub struct MyPPU {
     pub chr_rom: Vec<u8>,
+    // carts without CHR ROM have 8KB of CHR RAM in its place
+    pub chr_ram: bool,
     pub palette_table: [u8; 32],
-    pub vram: [u8; 2048],
+    // 2KB of console VRAM, the upper half only exists on four-screen carts
+    pub vram: [u8; 4096],
 
     pub oam_data: [u8; 256],
     pub oam_addr: u8,
 
     pub mirroring: Mirroring,
     pub control: ControlRegister,
-    pub addr: AddrRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
-    pub scroll: ScrollRegister,
+    pub loopy: LoopyRegisters,
 
     internal_buffer: u8,
 
+    // the data bus latch between CPU and PPU, what reads of write-only
+    // registers and unused status bits return
+    open_bus: u8,
+    open_bus_refreshed: [u64; 8],
+
     scanline: u16,
     cycles: usize,
+    // dots since power on
+    dots: u64,
 
     pub nmi_interrupt: Option<u8>,
+    // NMI enabled during vblank only fires after the next instruction
+    nmi_delayed: bool,
+    suppress_vblank: bool,
+    odd_frame: bool,
+    region: Region,
+
+    // Turning this off draws every sprite on a line instead of only the
+    // first eight. Removes flicker, but isn't what the hardware does.
+    pub sprite_limit: bool,
+    // Debugging and screenshot aids: hide the background, all sprites or
+    // single sprites (one bit per OAM index) from the picture. Sprite 0
+    // hits don't change.
+    pub show_background: bool,
+    pub show_sprites: bool,
+    pub hidden_sprites: u64,
+    // sprites the limit kept off some line this frame, one bit per OAM entry
+    dropped_sprites: u64,
+
+    // what the 9 bit pixels are shown as
+    pub palette: Palette,
+    // picture as drawn so far, complete once vblank starts
+    pub frame: Frame,
+    background: Background,
+    line_sprites: Vec<LineSprite>,
+
+    access_log: Option<Vec<MemAccess>>,
+    // events of the frame in progress, and of the last complete one
+    event_log: Option<Vec<PpuEvent>>,
+    frame_events: Vec<PpuEvent>,
+    pub cdl: Option<Rc<RefCell<CodeDataLogger>>>,
 }
 
 pub trait PPU {
@@ -38,11 +100,13 @@ pub trait PPU {
         fn read_status(&mut self) -> u8; 
         fn write_to_oam_addr(&mut self, value: u8);
         fn write_to_oam_data(&mut self, value: u8);
-        fn read_oam_data(&self) -> u8;
+        fn read_oam_data(&mut self) -> u8;
         fn write_to_scroll(&mut self, value: u8);
         fn write_to_ppu_addr(&mut self, value: u8);
         fn write_to_data(&mut self, value: u8);
         fn read_data(&mut self) -> u8;
+        fn write_to_status(&mut self, value: u8);
+        fn read_open_bus(&mut self) -> u8;
         fn write_oam_dma(&mut self, value: &[u8; 256]);
     }
 
@@ -52,27 +116,62 @@ impl MyPPU {
     }
 
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_ram = chr_rom.is_empty();
         MyPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            chr_rom: if chr_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_ram,
+            mirroring,
             control: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             oam_addr: 0,
-            scroll: ScrollRegister::new(),
-            addr: AddrRegister::new(),
-            vram: [0; 2048],
+            loopy: LoopyRegisters::new(),
+            vram: [0; 4096],
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_buffer: 0,
+            open_bus: 0,
+            open_bus_refreshed: [0; 8],
             scanline: 0,
             cycles: 0,
+            dots: 0,
             nmi_interrupt: None,
+            nmi_delayed: false,
+            suppress_vblank: false,
+            odd_frame: false,
+            region: Region::Ntsc,
+            sprite_limit: true,
+            show_background: true,
+            show_sprites: true,
+            hidden_sprites: 0,
+            dropped_sprites: 0,
+            palette: Palette::default(),
+            frame: Frame::new(),
+            background: Background::default(),
+            line_sprites: Vec::new(),
+            access_log: None,
+            event_log: None,
+            frame_events: Vec::new(),
+            cdl: None,
         }
     }
 
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
     fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.control.vram_addr_increment());
+        if self.rendering_active() {
+            // $2007 accesses during rendering bump v the way the fetches do
+            self.loopy.increment_coarse_x();
+            self.loopy.increment_y();
+        } else {
+            self.loopy.increment(self.control.vram_addr_increment());
+        }
     }
 
    // Horizontal:
@@ -82,6 +181,10 @@ impl MyPPU {
    // Vertical:
    //   [ A ] [ B ]
    //   [ a ] [ b ]
+
+   // Four-screen:
+   //   [ A ] [ B ]
+   //   [ C ] [ D ]
    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
@@ -95,139 +198,538 @@ impl MyPPU {
         }
     }
 
+    // Advances the PPU by `cycles` dots. Returns true once the frame is
+    // complete, i.e. when vblank starts.
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            self.cycles = self.cycles - 341;
-            self.scanline += 1;
-
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.control.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
+        let mut frame_complete = false;
+        let vblank_line = self.region.vblank_line();
+        let pre_render_line = self.region.pre_render_line();
+        for _ in 0..cycles {
+            match (self.scanline, self.cycles) {
+                (line, 1) if line == vblank_line => {
+                    // a $2002 read right before this dot cancels the whole
+                    // vblank start, see read_status
+                    if !self.suppress_vblank {
+                        self.status.set_vblank_status(true);
+                        if self.control.generate_vblank_nmi() {
+                            self.nmi_interrupt = Some(1);
+                        }
+                    }
+                    self.suppress_vblank = false;
+                    frame_complete = true;
+                    if let Some(log) = self.event_log.as_mut() {
+                        self.frame_events = std::mem::take(log);
+                    }
                 }
+                (line, 1) if line == pre_render_line => {
+                    self.status.reset_vblank_status();
+                    self.status.set_sprite_zero_hit(false);
+                    self.status.set_sprite_overflow(false);
+                    self.dropped_sprites = 0;
+                }
+                _ => {}
+            }
+            self.render_dot();
+
+            self.dots += 1;
+            self.cycles += 1;
+            if self.scanline == pre_render_line
+                && self.cycles == 340
+                && self.odd_frame
+                && self.region.skips_odd_frame_dot()
+                && self.rendering_enabled()
+            {
+                // odd frames are one dot shorter while rendering
+                self.cycles = 341;
             }
+            if self.cycles < 341 {
+                continue;
+            }
+            self.cycles = 0;
+            self.scanline += 1;
 
-            if self.scanline >= 262 {
+            if self.scanline > pre_render_line {
                 self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                return true;
+                self.odd_frame = !self.odd_frame;
+                // every dot is 8 master clocks, the subcarrier 12
+                self.frame.phase = (self.dots * 8 % 12) as u8;
             }
         }
-        return false
+        frame_complete
     }
 
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
+        if self.nmi_delayed {
+            self.nmi_delayed = false;
+            return None;
+        }
         self.nmi_interrupt.take()
     }
 
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn cycle(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn dropped_sprites(&self) -> u64 {
+        self.dropped_sprites
+    }
+
+    pub fn set_access_log(&mut self, enabled: bool) {
+        self.access_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_access_log(&mut self) -> Vec<MemAccess> {
+        match self.access_log.as_mut() {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_event_log(&mut self, enabled: bool) {
+        self.event_log = if enabled { Some(Vec::new()) } else { None };
+        self.frame_events.clear();
+    }
+
+    pub fn event_log_enabled(&self) -> bool {
+        self.event_log.is_some()
+    }
+
+    pub fn log_event(&mut self, addr: u16, value: u8) {
+        let (scanline, dot) = (self.scanline, self.cycles);
+        if let Some(log) = self.event_log.as_mut() {
+            log.push(PpuEvent { addr, value, scanline, dot });
+        }
+    }
+
+    // Events from one vblank start to the next
+    pub fn events(&self) -> &[PpuEvent] {
+        &self.frame_events
+    }
+
+    fn log_access(&mut self, addr: u16, value: u8, kind: AccessKind) {
+        if let Some(log) = self.access_log.as_mut() {
+            log.push(MemAccess { addr, value, kind });
+        }
+    }
+
+    // Side-effect free view of the $2000-$2007 registers for debugging tools
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => self.status.snapshot(),
+            0x2004 => self.oam_data[self.oam_addr as usize],
+            0x2007 => self.internal_buffer,
+            _ => 0,
+        }
+    }
+
+    // Side-effect free read of the PPU address space
+    pub fn peek(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0..=0x1fff => self.chr_rom.get(addr as usize).copied().unwrap_or(0),
+            0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    // Drives the bits in `mask` of the I/O latch with `value`
+    fn refresh_open_bus(&mut self, value: u8, mask: u8) {
+        self.open_bus = (self.open_bus & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.open_bus_refreshed[bit] = self.dots;
+            }
+        }
+    }
+
+    fn decayed_open_bus(&mut self) -> u8 {
+        for bit in 0..8 {
+            if self.dots - self.open_bus_refreshed[bit] > OPEN_BUS_DECAY_DOTS {
+                self.open_bus &= !(1 << bit);
+            }
+        }
+        self.open_bus
+    }
+
+}
+
+//Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let idx = (addr - 0x3f00) as usize % 32;
+    if idx >= 0x10 && idx.is_multiple_of(4) {
+        idx - 0x10
+    } else {
+        idx
+    }
 }
 
 impl PPU for MyPPU {
     fn write_to_ctrl(&mut self, value: u8) {
+        self.refresh_open_bus(value, 0xff);
         let before_nmi_status = self.control.generate_vblank_nmi();
         self.control.update(value);
+        self.loopy.write_ctrl(value);
 
         if !before_nmi_status && self.control.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
+            self.nmi_delayed = true;
+        }
+        // turning NMI off right as vblank starts drops the pending NMI
+        if !self.control.generate_vblank_nmi() && self.scanline == self.region.vblank_line()
+            && self.cycles <= 3 {
+            self.nmi_interrupt = None;
         }
     }
 
     fn write_to_mask(&mut self, value: u8) {
+        self.refresh_open_bus(value, 0xff);
         self.mask.update(value);
     }
 
     fn read_status(&mut self) -> u8 {
-        let data = self.status.snapshot();
+        // only the top three bits are driven, the rest is open bus
+        let data = (self.status.snapshot() & 0xe0) | (self.decayed_open_bus() & 0x1f);
+        self.refresh_open_bus(data, 0xe0);
         self.status.reset_vblank_status();
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        if self.scanline == self.region.vblank_line() {
+            match self.cycles {
+                // one dot before the flag goes up: reads clear, and the
+                // flag and NMI never happen this frame
+                1 => self.suppress_vblank = true,
+                // on the same dot or one after: reads set, but no NMI
+                2 | 3 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
+        self.loopy.reset_latch();
         data
     }
 
     fn write_to_oam_addr(&mut self, value: u8) {
+        self.refresh_open_bus(value, 0xff);
         self.oam_addr = value;
     }
 
     fn write_to_oam_data(&mut self, value: u8) {
+        self.refresh_open_bus(value, 0xff);
+        if self.rendering_active() {
+            // OAM is busy with sprite evaluation, the write is dropped and
+            // only the sprite index part of OAMADDR moves on
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
-    fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+    fn read_oam_data(&mut self) -> u8 {
+        let mut data = self.oam_data[self.oam_addr as usize];
+        if self.oam_addr & 0b11 == 2 {
+            // unimplemented bits of the sprite attribute byte
+            data &= 0xe3;
+        }
+        self.refresh_open_bus(data, 0xff);
+        data
     }
 
     fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.refresh_open_bus(value, 0xff);
+        self.loopy.write_scroll(value);
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.refresh_open_bus(value, 0xff);
+        self.loopy.write_addr(value);
     }
 
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        self.refresh_open_bus(value, 0xff);
+        let addr = self.loopy.addr();
+        self.log_access(addr, value, AccessKind::Write);
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr), 
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+            0..=0x1fff => {
+                if self.chr_ram {
+                    self.chr_rom[addr as usize] = value;
+                }
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
+            0x2000..=0x3eff => {
+                self.vram[self.mirror_vram_addr(addr) as usize] = value;
             }
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(addr - 0x3f00) as usize] = value;
+            _ => {
+                self.palette_table[palette_index(addr)] = value;
             }
-            _ => panic!("unexpected access to mirrored space {}", addr),
         }
         self.increment_vram_addr();
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
 
         self.increment_vram_addr();
 
-        match addr {
+        let data = match addr {
             0..=0x1fff => {
                 let result = self.internal_buffer;
                 self.internal_buffer = self.chr_rom[addr as usize];
+                if let Some(cdl) = self.cdl.as_ref() {
+                    cdl.borrow_mut().log_chr_read(addr);
+                }
+                self.refresh_open_bus(result, 0xff);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_buffer;
                 self.internal_buffer = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.refresh_open_bus(result, 0xff);
                 result
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
+            _ => {
+                // palette reads skip the buffer, which gets filled from the
+                // nametable byte "under" the palette instead; the top two
+                // bits aren't driven and come from open bus
+                self.internal_buffer = self.vram[self.mirror_vram_addr(addr - 0x1000) as usize];
+                let result = (self.palette_table[palette_index(addr)] & 0x3f) | (self.decayed_open_bus() & 0xc0);
+                self.refresh_open_bus(result, 0x3f);
+                result
             }
+        };
+        self.log_access(addr, data, AccessKind::Read);
+        data
+    }
 
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(addr - 0x3f00) as usize]
-            }
-            _ => panic!("unexpected access to mirrored space {}", addr),
-        }
+    fn write_to_status(&mut self, value: u8) {
+        // read only, but the write still lands on the latch
+        self.refresh_open_bus(value, 0xff);
+    }
+
+    fn read_open_bus(&mut self) -> u8 {
+        self.decayed_open_bus()
     }
 
+    // Same as 256 writes to $2004: starts at OAMADDR and wraps around
     fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
-            self.oam_data[self.oam_addr as usize] = *x;
-            self.oam_addr = self.oam_addr.wrapping_add(1);
+            self.write_to_oam_data(*x);
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for MyPPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_ram {
+            w.write_bytes(&self.chr_rom);
+        }
+        w.write_bytes(&self.palette_table);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam_data);
+        w.write_u8(self.oam_addr);
+        w.write_u8(self.control.bits());
+        w.write_u8(self.mask.bits());
+        w.write_u8(self.status.bits());
+        self.loopy.save_state(w);
+        w.write_u8(self.internal_buffer);
+        w.write_u8(self.open_bus);
+        for refreshed in self.open_bus_refreshed.iter() {
+            w.write_u64(*refreshed);
+        }
+        w.write_u16(self.scanline);
+        w.write_u64(self.cycles as u64);
+        w.write_u64(self.dots);
+        w.write_bool(self.nmi_interrupt.is_some());
+        w.write_bool(self.nmi_delayed);
+        w.write_bool(self.suppress_vblank);
+        w.write_bool(self.odd_frame);
+        self.save_render_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if self.chr_ram {
+            r.read_into(&mut self.chr_rom)?;
+        }
+        r.read_into(&mut self.palette_table)?;
+        r.read_into(&mut self.vram)?;
+        r.read_into(&mut self.oam_data)?;
+        self.oam_addr = r.read_u8()?;
+        self.control.update(r.read_u8()?);
+        self.mask.update(r.read_u8()?);
+        self.status = StatusRegister::from_bits_truncate(r.read_u8()?);
+        self.loopy.load_state(r)?;
+        self.internal_buffer = r.read_u8()?;
+        self.open_bus = r.read_u8()?;
+        for refreshed in self.open_bus_refreshed.iter_mut() {
+            *refreshed = r.read_u64()?;
+        }
+        self.scanline = r.read_u16()?;
+        self.cycles = r.read_u64()? as usize;
+        self.dots = r.read_u64()?;
+        self.nmi_interrupt = if r.read_bool()? { Some(1) } else { None };
+        self.nmi_delayed = r.read_bool()?;
+        self.suppress_vblank = r.read_bool()?;
+        self.odd_frame = r.read_bool()?;
+        self.load_render_state(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_full_address_map() {
+        let mut ppu = MyPPU::new_empty_rom();
+        // $3000-$3EFF mirrors the nametables
+        ppu.write_to_ppu_addr(0x30);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x05], 0x66);
+
+        // $3F20-$3FFF mirrors the palette, $3F10 is $3F00
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0xf0);
+        ppu.write_to_data(0x21);
+        assert_eq!(ppu.palette_table[0x00], 0x21);
+        assert_eq!(ppu.peek(0x3f10), 0x21);
+
+        // addresses wrap at $3FFF
+        ppu.write_to_ppu_addr(0x7f);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.loopy.addr(), 0x3f00);
+    }
+
+    #[test]
+    fn test_palette_read_fills_buffer_from_nametable() {
+        let mut ppu = MyPPU::new_empty_rom();
+        // $2F05, with horizontal mirroring
+        ppu.vram[0x705] = 0x77;
+        ppu.palette_table[5] = 0x12;
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.read_data(), 0x12);
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        // the buffer now holds $2F05, the byte "under" $3F05
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    #[test]
+    fn test_open_bus_decay() {
+        let mut ppu = MyPPU::new_empty_rom();
+        ppu.write_to_mask(0);
+        ppu.write_to_scroll(0xbf);
+        assert_eq!(ppu.read_open_bus(), 0xbf);
+
+        // status only drives bits 7-5
+        ppu.status.set_vblank_status(true);
+        assert_eq!(ppu.read_status(), 0x80 | 0x1f);
+        assert_eq!(ppu.read_open_bus(), 0x9f);
+
+        for _ in 0..(OPEN_BUS_DECAY_DOTS / 255 + 1) {
+            ppu.tick(255);
+        }
+        assert_eq!(ppu.read_open_bus(), 0);
+    }
+
+    fn run_to(ppu: &mut MyPPU, line: u16, dot: usize) {
+        while ppu.scanline() != line || ppu.cycle() != dot {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_vblank_timing() {
+        let mut ppu = MyPPU::new_empty_rom();
+        ppu.write_to_ctrl(0x80);
+        run_to(&mut ppu, 241, 1);
+        assert!(!ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.poll_nmi_interrupt().is_some());
+
+        run_to(&mut ppu, 261, 1);
+        assert!(ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(!ppu.status.is_in_vblank());
+    }
+
+    #[test]
+    fn test_status_read_race() {
+        let mut ppu = MyPPU::new_empty_rom();
+        ppu.write_to_ctrl(0x80);
+
+        // one dot early: reads clear, no flag, no NMI
+        run_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.tick(10);
+        assert!(!ppu.status.is_in_vblank());
+        assert!(ppu.poll_nmi_interrupt().is_none());
+
+        // on the dot: reads set, but the NMI is gone
+        run_to(&mut ppu, 241, 2);
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        assert!(ppu.poll_nmi_interrupt().is_none());
+    }
+
+    #[test]
+    fn test_nmi_enabled_during_vblank_is_delayed() {
+        let mut ppu = MyPPU::new_empty_rom();
+        run_to(&mut ppu, 250, 0);
+        ppu.write_to_ctrl(0x80);
+        assert!(ppu.poll_nmi_interrupt().is_none());
+        assert!(ppu.poll_nmi_interrupt().is_some());
+    }
+
+    #[test]
+    fn test_odd_frames_skip_a_dot() {
+        let mut ppu = MyPPU::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+        run_to(&mut ppu, 0, 1);
+
+        let mut lengths = Vec::new();
+        for _ in 0..3 {
+            let start = ppu.dots;
+            ppu.tick(1);
+            run_to(&mut ppu, 0, 1);
+            lengths.push(ppu.dots - start);
+        }
+        assert_eq!(lengths, vec![89342, 89341, 89342]);
+
+        // not while rendering is off
+        ppu.write_to_mask(0);
+        let start = ppu.dots;
+        ppu.tick(1);
+        run_to(&mut ppu, 0, 1);
+        assert_eq!(ppu.dots - start, 89342);
+    }
+
+    #[test]
+    fn test_pal_and_dendy_frames() {
+        for (region, vblank_line) in [(Region::Pal, 241), (Region::Dendy, 291)].iter() {
+            let mut ppu = MyPPU::new_empty_rom();
+            ppu.set_region(*region);
+            ppu.write_to_mask(0b0000_1000);
+            run_to(&mut ppu, *vblank_line, 1);
+            assert!(!ppu.status.is_in_vblank());
+            ppu.tick(1);
+            assert!(ppu.status.is_in_vblank());
+
+            run_to(&mut ppu, 311, 1);
+            ppu.tick(1);
+            assert!(!ppu.status.is_in_vblank());
+
+            // 312 lines and no odd frame dot skip
+            run_to(&mut ppu, 0, 1);
+            for _ in 0..2 {
+                let start = ppu.dots;
+                ppu.tick(1);
+                run_to(&mut ppu, 0, 1);
+                assert_eq!(ppu.dots - start, 341 * 312);
+            }
+        }
+    }
+}
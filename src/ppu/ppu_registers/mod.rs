@@ -1,5 +1,4 @@
 pub mod ppu_status;
-pub mod ppu_addr;
 pub mod ppu_ctrl;
 pub mod ppu_mask;
-pub mod ppu_scroll;
\ No newline at end of file
+pub mod ppu_loopy;
\ No newline at end of file
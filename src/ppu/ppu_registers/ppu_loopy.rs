@@ -0,0 +1,166 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// Internal scroll/address registers shared by $2000, $2005 and $2006.
+// https://www.nesdev.org/wiki/PPU_scrolling
+//
+// v and t use the same layout:
+// yyy NN YYYYY XXXXX
+// ||| || ||||| +++++-- coarse X scroll
+// ||| || +++++-------- coarse Y scroll
+// ||| ++-------------- nametable select
+// +++----------------- fine Y scroll
+#[derive(Default)]
+pub struct LoopyRegisters {
+    // current VRAM address, also where rendering fetches from
+    pub v: u16,
+    // temporary address, the top left corner of the screen
+    pub t: u16,
+    // fine X scroll
+    pub x: u8,
+    // first/second write toggle shared by $2005 and $2006
+    pub w: bool,
+}
+
+impl LoopyRegisters {
+    pub fn new() -> Self {
+        LoopyRegisters {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+        }
+    }
+
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !0x0c00) | ((data & 0b11) as u16) << 10;
+    }
+
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001f) | (data >> 3) as u16;
+            self.x = data & 0x07;
+        } else {
+            self.t = (self.t & !0x73e0) | ((data & 0x07) as u16) << 12 | ((data >> 3) as u16) << 5;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            // the topmost bit of t is cleared by the first write
+            self.t = (self.t & 0x00ff) | ((data & 0x3f) as u16) << 8;
+        } else {
+            self.t = (self.t & 0xff00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // PPU address $2007 accesses go to
+    pub fn addr(&self) -> u16 {
+        self.v & 0x3fff
+    }
+
+    pub fn increment(&mut self, inc: u8) {
+        self.v = self.v.wrapping_add(inc as u16) & 0x7fff;
+    }
+
+    pub fn increment_coarse_x(&mut self) {
+        if self.v & 0x001f == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03e0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // attribute rows wrap without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03e0) | coarse_y << 5;
+    }
+
+    // dot 257 of every rendered line
+    pub fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041f) | (self.t & 0x041f);
+    }
+
+    // dots 280-304 of the pre-render line
+    pub fn copy_vertical(&mut self) {
+        self.v = (self.v & 0x041f) | (self.t & !0x041f);
+    }
+}
+
+impl Savestate for LoopyRegisters {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.x);
+        w.write_bool(self.w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        self.x = r.read_u8()?;
+        self.w = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_register_interaction() {
+        // the write sequence from the nesdev wiki scrolling summary
+        let mut loopy = LoopyRegisters::new();
+        loopy.write_ctrl(0b10);
+        assert_eq!(loopy.t, 0x0800);
+
+        loopy.write_scroll(0b0111_1101);
+        assert_eq!(loopy.t, 0x080f);
+        assert_eq!(loopy.x, 0b101);
+        assert!(loopy.w);
+
+        loopy.write_scroll(0b0101_1110);
+        assert_eq!(loopy.t, 0x696f);
+        assert!(!loopy.w);
+
+        loopy.write_addr(0b0011_1101);
+        assert_eq!(loopy.t, 0x3d6f);
+        loopy.write_addr(0b1111_0000);
+        assert_eq!(loopy.t, 0x3df0);
+        assert_eq!(loopy.v, 0x3df0);
+    }
+
+    #[test]
+    fn test_increments_wrap_nametables() {
+        let mut loopy = LoopyRegisters::new();
+        loopy.v = 31;
+        loopy.increment_coarse_x();
+        assert_eq!(loopy.v, 0x0400);
+
+        loopy.v = 0x7000 | 29 << 5;
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0x0800);
+    }
+}
@@ -0,0 +1,669 @@
+// Dot based rendering pipeline.
+// https://www.nesdev.org/wiki/PPU_rendering
+//
+// Every visible and pre-render scanline the background is fetched eight dots
+// per tile (nametable, attribute, pattern low, pattern high) into latches,
+// which feed 16 bit shift registers the pixels are taken from. Sprites for
+// the next line are evaluated into secondary OAM at the end of the visible
+// part of each line and their patterns fetched right after.
+//
+// Fetches go through the loopy v register, see ppu_loopy.
+
+use super::MyPPU;
+use crate::cdl::ChrLayer;
+use crate::region::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+pub const VISIBLE_LINES: u16 = 240;
+const MAX_SPRITES_PER_LINE: usize = 8;
+
+#[derive(Default)]
+pub struct Background {
+    next_tile: u8,
+    next_attr: u8,
+    next_lo: u8,
+    next_hi: u8,
+
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LineSprite {
+    pub x: u8,
+    pub attr: u8,
+    pub lo: u8,
+    pub hi: u8,
+    // OAM index
+    pub index: u8,
+}
+
+impl LineSprite {
+    // 2 bit colour of the sprite at screen column `x`, 0 if transparent there
+    fn pixel(&self, x: usize) -> u8 {
+        let offset = x.wrapping_sub(self.x as usize);
+        if offset >= 8 {
+            return 0;
+        }
+        let bit = if self.attr & 0x40 != 0 { offset } else { 7 - offset };
+        ((self.hi >> bit) & 1) << 1 | ((self.lo >> bit) & 1)
+    }
+}
+
+impl MyPPU {
+    pub(super) fn rendering_enabled(&self) -> bool {
+        self.mask.show_bkg() || self.mask.show_sprites()
+    }
+
+    // Whether the PPU is busy fetching right now
+    pub(super) fn rendering_active(&self) -> bool {
+        let line = self.scanline;
+        self.rendering_enabled() && (line < VISIBLE_LINES || line == self.region.pre_render_line())
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_rom.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    fn load_background_shifters(&mut self) {
+        let bg = &mut self.background;
+        bg.pattern_lo = (bg.pattern_lo & 0xff00) | bg.next_lo as u16;
+        bg.pattern_hi = (bg.pattern_hi & 0xff00) | bg.next_hi as u16;
+        bg.attr_lo = (bg.attr_lo & 0xff00) | if bg.next_attr & 0b01 != 0 { 0xff } else { 0 };
+        bg.attr_hi = (bg.attr_hi & 0xff00) | if bg.next_attr & 0b10 != 0 { 0xff } else { 0 };
+    }
+
+    fn shift_background(&mut self) {
+        let bg = &mut self.background;
+        bg.pattern_lo <<= 1;
+        bg.pattern_hi <<= 1;
+        bg.attr_lo <<= 1;
+        bg.attr_hi <<= 1;
+    }
+
+    fn fetch_background(&mut self, step: usize) {
+        let v = self.loopy.v;
+        match step {
+            0 => {
+                self.load_background_shifters();
+                let addr = 0x2000 | (v & 0x0fff);
+                self.background.next_tile = self.vram[self.mirror_vram_addr(addr) as usize];
+            }
+            2 => {
+                let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let attr = self.vram[self.mirror_vram_addr(addr) as usize];
+                let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                self.background.next_attr = (attr >> shift) & 0b11;
+            }
+            4 | 6 => {
+                let tile = self.control.bknd_pattern_addr() + self.background.next_tile as u16 * 16;
+                let addr = tile + ((v >> 12) & 0x07) + if step == 6 { 8 } else { 0 };
+                let data = self.read_chr(addr);
+                if step == 4 {
+                    self.background.next_lo = data;
+                } else {
+                    self.background.next_hi = data;
+                }
+                if let Some(cdl) = self.cdl.as_ref() {
+                    cdl.borrow_mut().log_chr_tile(tile, ChrLayer::Background);
+                }
+            }
+            7 => self.loopy.increment_coarse_x(),
+            _ => {}
+        }
+    }
+
+    // Picks the sprites that show up on the line after the current one and
+    // fetches their pattern data.
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    fn evaluate_sprites(&mut self) {
+        self.line_sprites.clear();
+        let line = self.scanline as usize;
+        let height = self.control.sprite_size() as usize;
+        // sprites are drawn one line below their OAM Y
+        let in_range = |y: u8| line.wrapping_sub(y as usize) < height;
+
+        let mut found = Vec::new();
+        let mut n = 0;
+        while n < 64 && found.len() < MAX_SPRITES_PER_LINE {
+            if in_range(self.oam_data[n * 4]) {
+                found.push(n);
+            }
+            n += 1;
+        }
+
+        // Once secondary OAM is full the hardware keeps going, but wrongly
+        // increments the byte offset along with the sprite index, so it
+        // compares tile numbers, attributes and X positions against the
+        // line instead of Y. That is where both the false positives and the
+        // false negatives of the overflow flag come from.
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+
+        let last = found.last().map_or(0, |i| i + 1);
+        let rest = (last..64).filter(|i| in_range(self.oam_data[i * 4]));
+        if self.sprite_limit {
+            for i in rest {
+                self.dropped_sprites |= 1 << i;
+            }
+        } else {
+            found.extend(rest);
+        }
+
+        for i in found {
+            let row = line - self.oam_data[i * 4] as usize;
+            let tile = self.oam_data[i * 4 + 1] as u16;
+            let attr = self.oam_data[i * 4 + 2];
+            // vertical flip spans both tiles of an 8x16 sprite
+            let row = if attr & 0x80 != 0 { height - 1 - row } else { row } as u16;
+            let base = if height == 16 {
+                // bit 0 of the index picks the pattern table, the top half
+                // uses the even tile and the bottom half the one after it
+                let bank = (tile & 1) * 0x1000;
+                bank + (tile & 0xfe) * 16 + if row >= 8 { 16 } else { 0 }
+            } else {
+                self.control.sprt_pattern_addr() + tile * 16
+            };
+            let row = row & 0x07;
+            if let Some(cdl) = self.cdl.as_ref() {
+                cdl.borrow_mut().log_chr_tile(base, ChrLayer::Sprite);
+            }
+            self.line_sprites.push(LineSprite {
+                x: self.oam_data[i * 4 + 3],
+                attr,
+                lo: self.read_chr(base + row),
+                hi: self.read_chr(base + row + 8),
+                index: i as u8,
+            });
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let x = self.cycles - 1;
+        let y = self.scanline as usize;
+
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask.show_bkg() && (x >= 8 || self.mask.leftmost_8pxl_bkg()) {
+            let bg = &self.background;
+            let mux = 0x8000 >> self.loopy.x;
+            let bit = |reg: u16| (reg & mux != 0) as u8;
+            bg_pixel = bit(bg.pattern_hi) << 1 | bit(bg.pattern_lo);
+            bg_palette = bit(bg.attr_hi) << 1 | bit(bg.attr_lo);
+        }
+
+        let mut sprite = None;
+        if self.mask.show_sprites() && (x >= 8 || self.mask.leftmost_8pxl_sprite()) {
+            sprite = self.front_sprite(x, 0);
+        }
+
+        // clipped pixels count as transparent here as well
+        if let Some((_, LineSprite { index: 0, .. })) = sprite {
+            if bg_pixel != 0 && x != 255 {
+                self.status.set_sprite_zero_hit(true);
+            }
+        }
+
+        // The layer toggles only change the picture, sprite 0 hits above
+        // still see everything
+        if !self.show_background {
+            bg_pixel = 0;
+        }
+        if !self.show_sprites {
+            sprite = None;
+        } else if self.hidden_sprites != 0 && sprite.is_some() {
+            sprite = self.front_sprite(x, self.hidden_sprites);
+        }
+
+        // Only the first opaque sprite in OAM order is looked at. If it is
+        // behind the background, the background wins even where a later
+        // front priority sprite is opaque too, so a back priority sprite
+        // masks out the sprites after it.
+        let palette_idx = match (sprite, bg_pixel) {
+            (Some((pixel, s)), 0) => 0x10 + (s.attr & 0b11) * 4 + pixel,
+            (Some((pixel, s)), _) if s.attr & 0x20 == 0 => 0x10 + (s.attr & 0b11) * 4 + pixel,
+            (_, 0) => 0,
+            (_, pixel) => bg_palette * 4 + pixel,
+        };
+        let color = self.palette_table[palette_idx as usize];
+        self.put_pixel(x, y, color);
+    }
+
+    // The first opaque sprite at column `x` and its colour, leaving out the
+    // sprites set in `hidden`
+    fn front_sprite(&self, x: usize, hidden: u64) -> Option<(u8, LineSprite)> {
+        self.line_sprites
+            .iter()
+            .filter(|s| hidden & (1 << s.index) == 0)
+            .map(|s| (s.pixel(x), *s))
+            .find(|(pixel, _)| *pixel != 0)
+    }
+
+    // Final palette lookup, with the PPUMASK greyscale and emphasis bits
+    fn put_pixel(&mut self, x: usize, y: usize, color: u8) {
+        let mut color = color & 0x3f;
+        if self.mask.is_greyscale() {
+            color &= 0x30;
+        }
+        let mut emphasis = (self.mask.bits() & 0xe0) as u16;
+        if self.region != Region::Ntsc {
+            // the 2C07 has the red and green bits the other way round
+            emphasis = emphasis & 0x80 | (emphasis & 0x20) << 1 | (emphasis & 0x40) >> 1;
+        }
+        let pixel = color as u16 | emphasis << 1;
+        self.frame.set_pixel(x, y, self.palette.rgb(pixel));
+        self.frame.set_raw_pixel(x, y, pixel);
+    }
+
+    // Runs the rendering work for the current dot
+    pub(super) fn render_dot(&mut self) {
+        let line = self.scanline;
+        let dot = self.cycles;
+        let visible = line < VISIBLE_LINES;
+        let pre_render = line == self.region.pre_render_line();
+        if !visible && !pre_render {
+            return;
+        }
+
+        if !self.rendering_enabled() {
+            // no fetches and no v updates, only the backdrop is shown; or
+            // the palette entry v points at, if it points into the palette
+            if visible && (1..=256).contains(&dot) {
+                let v = self.loopy.addr();
+                let color = if v >= 0x3f00 { self.peek(v) } else { self.palette_table[0] };
+                self.put_pixel(dot - 1, line as usize, color);
+            }
+            return;
+        }
+
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+            self.fetch_background((dot - 1) % 8);
+        }
+        if visible && (1..=256).contains(&dot) {
+            self.output_pixel();
+        }
+
+        match dot {
+            256 => self.loopy.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                self.loopy.copy_horizontal();
+                if visible {
+                    self.evaluate_sprites();
+                } else {
+                    self.line_sprites.clear();
+                }
+            }
+            280..=304 if !visible => self.loopy.copy_vertical(),
+            _ => {}
+        }
+        if (257..=320).contains(&dot) {
+            // sprite pattern fetches leave OAMADDR at 0
+            self.oam_addr = 0;
+        }
+    }
+
+    pub(super) fn save_render_state(&self, w: &mut StateWriter) {
+        let bg = &self.background;
+        for value in [bg.next_tile, bg.next_attr, bg.next_lo, bg.next_hi].iter() {
+            w.write_u8(*value);
+        }
+        for value in [bg.pattern_lo, bg.pattern_hi, bg.attr_lo, bg.attr_hi].iter() {
+            w.write_u16(*value);
+        }
+        w.write_u8(self.line_sprites.len() as u8);
+        for sprite in self.line_sprites.iter() {
+            w.write_u8(sprite.x);
+            w.write_u8(sprite.attr);
+            w.write_u8(sprite.lo);
+            w.write_u8(sprite.hi);
+            w.write_u8(sprite.index);
+        }
+    }
+
+    pub(super) fn load_render_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let bg = &mut self.background;
+        bg.next_tile = r.read_u8()?;
+        bg.next_attr = r.read_u8()?;
+        bg.next_lo = r.read_u8()?;
+        bg.next_hi = r.read_u8()?;
+        bg.pattern_lo = r.read_u16()?;
+        bg.pattern_hi = r.read_u16()?;
+        bg.attr_lo = r.read_u16()?;
+        bg.attr_hi = r.read_u16()?;
+        self.line_sprites.clear();
+        for _ in 0..r.read_u8()? {
+            self.line_sprites.push(LineSprite {
+                x: r.read_u8()?,
+                attr: r.read_u8()?,
+                lo: r.read_u8()?,
+                hi: r.read_u8()?,
+                index: r.read_u8()?,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::Mirroring;
+    use crate::graphics_data::palette;
+    use crate::ppu::ppu_registers::ppu_mask::Color;
+    use crate::ppu::ppu_registers::ppu_status::StatusRegister;
+    use crate::ppu::PPU;
+
+    fn pixel(ppu: &MyPPU, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * 256 + x) * 3;
+        let data = &ppu.frame.data;
+        (data[base], data[base + 1], data[base + 2])
+    }
+
+    fn run_until_line(ppu: &mut MyPPU, line: u16) {
+        while ppu.scanline() != line {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_change() {
+        let mut chr = vec![0; 0x2000];
+        // tile 1 is solid colour 1
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        for row in 0..30 {
+            ppu.vram[row * 32 + 1] = 1;
+        }
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_to_mask(0b0000_1010);
+
+        run_until_line(&mut ppu, 100);
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(0);
+        run_until_line(&mut ppu, 241);
+
+        let black = palette::SYSTEM_PALLETE[0x0f];
+        let white = palette::SYSTEM_PALLETE[0x30];
+        assert_eq!(pixel(&ppu, 0, 50), black);
+        assert_eq!(pixel(&ppu, 8, 50), white);
+        assert_eq!(pixel(&ppu, 0, 120), white);
+        assert_eq!(pixel(&ppu, 8, 120), black);
+    }
+
+    #[test]
+    fn test_scroll_into_neighbouring_nametables() {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::VERTICAL);
+        // top left tile of the $2400 and $2800 (= $2000) nametables
+        ppu.vram[0x400] = 1;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_to_mask(0b0000_1010);
+        // start on the pre-render line so the vertical scroll gets copied
+        run_until_line(&mut ppu, 250);
+        ppu.write_to_scroll(248);
+        ppu.write_to_scroll(0);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+
+        let black = palette::SYSTEM_PALLETE[0x0f];
+        let white = palette::SYSTEM_PALLETE[0x30];
+        assert_eq!(pixel(&ppu, 0, 0), black);
+        assert_eq!(pixel(&ppu, 8, 0), white);
+        assert_eq!(pixel(&ppu, 15, 7), white);
+        assert_eq!(pixel(&ppu, 8, 8), black);
+    }
+
+    #[test]
+    fn test_mask_controls() {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 1;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+
+        // background on, left column clipped, greyscale
+        ppu.write_to_mask(0b0000_1001);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 0, 1), palette::SYSTEM_PALLETE[0x0f & 0x30]);
+        assert_eq!(pixel(&ppu, 8, 1), palette::SYSTEM_PALLETE[0x16 & 0x30]);
+
+        // blue emphasis only
+        ppu.write_to_mask(0b1000_1010);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        let red = palette::SYSTEM_PALLETE[0x16];
+        assert_eq!(pixel(&ppu, 0, 1), palette::emphasize(red, &[Color::Blue]));
+        assert!(pixel(&ppu, 0, 1).0 < red.0);
+
+        // with rendering off nothing touches v
+        ppu.write_to_mask(0);
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x08);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(ppu.loopy.v, 0x2108);
+        assert_eq!(pixel(&ppu, 8, 1), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_pal_emphasis_swaps_red_and_green() {
+        let mut ppu = MyPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x16;
+        ppu.write_to_mask(0b0010_1000);
+        run_until_line(&mut ppu, 241);
+        // bit 5 is red on NTSC
+        assert_eq!(ppu.frame.pixels[256] >> 6, 0b001);
+
+        ppu.set_region(Region::Pal);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        // and green on PAL, while blue stays put
+        assert_eq!(ppu.frame.pixels[256] >> 6, 0b010);
+        ppu.write_to_mask(0b1100_1000);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(ppu.frame.pixels[256] >> 6, 0b101);
+        assert_eq!(pixel(&ppu, 0, 1), palette::emphasize(palette::SYSTEM_PALLETE[0x16], &[Color::Red, Color::Blue]));
+    }
+
+    #[test]
+    fn test_sprites_limited_per_line() {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.palette_table[0x11] = 0x16;
+        // nine sprites side by side on line 20
+        for i in 0..9 {
+            ppu.oam_data[i * 4] = 19;
+            ppu.oam_data[i * 4 + 1] = 1;
+            ppu.oam_data[i * 4 + 3] = (i * 8) as u8;
+        }
+        for i in 9..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        ppu.write_to_mask(0b0001_0100);
+        run_until_line(&mut ppu, 241);
+
+        let red = palette::SYSTEM_PALLETE[0x16];
+        assert_eq!(pixel(&ppu, 0, 20), red);
+        assert_eq!(pixel(&ppu, 63, 27), red);
+        assert_ne!(pixel(&ppu, 64, 20), red);
+        assert_ne!(pixel(&ppu, 0, 19), red);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+        assert_eq!(ppu.dropped_sprites(), 1 << 8);
+
+        ppu.sprite_limit = false;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 64, 20), red);
+        assert_eq!(ppu.dropped_sprites(), 0);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.vram[2 * 32 + 4] = 1;
+        // sprite 0 overlaps the tile at column 4, row 2 by a single column
+        ppu.oam_data[0] = 20;
+        ppu.oam_data[1] = 1;
+        ppu.oam_data[3] = 39;
+        for i in 1..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        ppu.write_to_mask(0b0001_1110);
+
+        run_until_line(&mut ppu, 21);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        run_until_line(&mut ppu, 22);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // never hits at x=255
+        ppu.vram[2 * 32 + 31] = 1;
+        ppu.oam_data[3] = 255;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        // nor in the left 8 pixels while they are clipped
+        ppu.vram[2 * 32] = 1;
+        ppu.oam_data[3] = 0;
+        ppu.write_to_mask(0b0001_1010);
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.vram[2 * 32 + 4] = 1;
+        ppu.palette_table[1] = 0x30;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.palette_table[0x15] = 0x1a;
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        // sprite 0 behind the background tile, sprite 1 in front and
+        // half over it, sprite 2 behind but over nothing
+        ppu.oam_data[0..4].copy_from_slice(&[15, 1, 0x20, 32]);
+        ppu.oam_data[4..8].copy_from_slice(&[15, 1, 0x01, 36]);
+        ppu.oam_data[8..12].copy_from_slice(&[15, 1, 0x20, 100]);
+        ppu.write_to_mask(0b0001_1110);
+        run_until_line(&mut ppu, 241);
+
+        let white = palette::SYSTEM_PALLETE[0x30];
+        let red = palette::SYSTEM_PALLETE[0x16];
+        let green = palette::SYSTEM_PALLETE[0x1a];
+        assert_eq!(pixel(&ppu, 33, 16), white);
+        // sprite 0 comes first and is behind, so sprite 1 is masked too
+        assert_eq!(pixel(&ppu, 37, 16), white);
+        assert_eq!(pixel(&ppu, 41, 16), green);
+        assert_eq!(pixel(&ppu, 101, 16), red);
+
+        // hiding sprite 0 uncovers sprite 1, without affecting the hit
+        ppu.hidden_sprites = 1;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 37, 16), green);
+        assert_eq!(pixel(&ppu, 33, 16), white);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        ppu.hidden_sprites = 0;
+        ppu.show_background = false;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 33, 16), red);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        ppu.show_sprites = false;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 41, 16), palette::SYSTEM_PALLETE[0]);
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        let mut chr = vec![0; 0x2000];
+        // tiles $02/$03 of the $1000 table: colour 1 on top, 2 below
+        for row in 0..8 {
+            chr[0x1020 + row] = 0xff;
+            chr[0x1030 + row + 8] = 0xff;
+        }
+        let mut ppu = MyPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.palette_table[0x11] = 0x16;
+        ppu.palette_table[0x12] = 0x2a;
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        ppu.oam_data[0] = 49;
+        ppu.oam_data[1] = 0x03;
+        ppu.oam_data[3] = 100;
+        ppu.write_to_ctrl(0b0010_0000);
+        ppu.write_to_mask(0b0001_0000);
+        run_until_line(&mut ppu, 241);
+
+        let top = palette::SYSTEM_PALLETE[0x16];
+        let bottom = palette::SYSTEM_PALLETE[0x2a];
+        assert_eq!(pixel(&ppu, 100, 50), top);
+        assert_eq!(pixel(&ppu, 107, 57), top);
+        assert_eq!(pixel(&ppu, 100, 58), bottom);
+        assert_eq!(pixel(&ppu, 100, 65), bottom);
+        assert_ne!(pixel(&ppu, 100, 66), bottom);
+
+        ppu.oam_data[2] = 0x80;
+        run_until_line(&mut ppu, 0);
+        run_until_line(&mut ppu, 241);
+        assert_eq!(pixel(&ppu, 100, 50), bottom);
+        assert_eq!(pixel(&ppu, 100, 65), top);
+    }
+
+    #[test]
+    fn test_overflow_evaluation_bug() {
+        let mut ppu = MyPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL);
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        for i in 0..8 {
+            ppu.oam_data[i * 4] = 10;
+        }
+        // the ninth sprite is out of range, so the buggy scan goes on to
+        // byte 1 (the tile number) of the tenth, which happens to be in range
+        ppu.oam_data[9 * 4 + 1] = 10;
+        ppu.write_to_mask(0b0001_0000);
+        run_until_line(&mut ppu, 12);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+}
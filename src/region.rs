@@ -0,0 +1,226 @@
+// Console timing variants.
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+use crate::cart::Rom;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclone timing: PAL frame length with NTSC-like CPU/PPU ratio
+    Dendy,
+}
+
+// GoodNES tags for dumps shared by several countries, one letter each
+const COUNTRY_COMBINATIONS: [&str; 5] = ["ju", "ue", "je", "jue", "eu"];
+
+// One No-Intro or GoodNES region tag, lowercase
+fn region_tag(tag: &str) -> Option<Region> {
+    match tag {
+        "dendy" | "russia" | "r" => Some(Region::Dendy),
+        "usa" | "u" | "japan" | "j" | "korea" | "k" | "canada" | "c" | "world" | "w" | "ntsc" => Some(Region::Ntsc),
+        "europe" | "e" | "australia" | "a" | "germany" | "g" | "france" | "f" | "spain" | "s" | "italy" | "i"
+        | "sweden" | "sw" | "netherlands" | "uk" | "scandinavia" | "pal" => Some(Region::Pal),
+        _ => None,
+    }
+}
+
+impl Region {
+    pub fn parse(name: &str) -> Result<Region, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("unknown region '{}', expected ntsc, pal or dendy", name)),
+        }
+    }
+
+    // The NES 2.0 header, then the database, then the file name; None
+    // when none of them can tell
+    pub fn detect(rom: &Rom, database: &RomDatabase, rom_name: &str) -> Option<Region> {
+        rom.region
+            .or_else(|| database.region(rom.crc32()))
+            .or_else(|| Region::from_rom_name(rom_name))
+    }
+
+    // Last resort for ROMs neither the header nor the database knows: the
+    // region tags of the No-Intro and GoodNES naming conventions, like
+    // "(Europe)", "(USA, Europe)", "(E)" or "(JU)". Only a guess, file names
+    // can say anything. Dumps shared by NTSC and PAL releases run on NTSC
+    // timing, the same as a NES 2.0 multi-region header.
+    pub fn from_rom_name(name: &str) -> Option<Region> {
+        let name = name.to_ascii_lowercase();
+        let mut found = Vec::new();
+        for group in name.split('(').skip(1).filter_map(|group| group.split(')').next()) {
+            for tag in group.split(',').map(str::trim) {
+                if COUNTRY_COMBINATIONS.contains(&tag) {
+                    found.extend(tag.chars().filter_map(|c| region_tag(&c.to_string())));
+                } else if let Some(region) = region_tag(tag) {
+                    found.push(region);
+                }
+            }
+        }
+
+        if found.contains(&Region::Dendy) {
+            Some(Region::Dendy)
+        } else if found.contains(&Region::Ntsc) {
+            Some(Region::Ntsc)
+        } else if found.contains(&Region::Pal) {
+            Some(Region::Pal)
+        } else {
+            None
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+
+    // PPU dots per CPU cycle, in fifths: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn ppu_dots_per_cpu_cycle_x5(&self) -> u8 {
+        match self {
+            Region::Ntsc | Region::Dendy => 15,
+            Region::Pal => 16,
+        }
+    }
+
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    pub fn pre_render_line(&self) -> u16 {
+        self.scanlines() - 1
+    }
+
+    // Line vblank (and the NMI) starts on, at dot 1
+    pub fn vblank_line(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // Dendy idles for 51 lines after the picture before vblank
+            Region::Dendy => 291,
+        }
+    }
+
+    // Only NTSC drops a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let dots = 341.0 * self.scanlines() as f64;
+        let dots_per_cycle = self.ppu_dots_per_cpu_cycle_x5() as f64 / 5.0;
+        let dots = if self.skips_odd_frame_dot() { dots - 0.5 } else { dots };
+        self.cpu_clock_hz() as f64 * dots_per_cycle / dots
+    }
+
+    // CPU cycles at which the APU frame counter steps in 4-step and 5-step
+    // mode; Dendy's APU runs on the NTSC schedule
+    pub fn frame_counter_steps(&self, five_step: bool) -> &'static [u32] {
+        match (self, five_step) {
+            (Region::Pal, false) => &[8313, 16627, 24939, 33252, 33253],
+            (Region::Pal, true) => &[8313, 16627, 24939, 33253, 41565, 41566],
+            (_, false) => &[7457, 14913, 22371, 29828, 29829],
+            (_, true) => &[7457, 14913, 22371, 29829, 37281, 37282],
+        }
+    }
+}
+
+// Regions of known dumps, for iNES 1.0 ROMs whose header can't say what
+// they were made for. One dump per line: the CRC32 of PRG + CHR ROM in hex
+// (see Rom::crc32), the region, and optionally the dump's name, e.g.
+//   0123abcd pal Some Game (Europe)
+// Lines starting with # are comments.
+#[derive(Default)]
+pub struct RomDatabase {
+    regions: HashMap<u32, Region>,
+}
+
+impl RomDatabase {
+    pub fn load(path: &str) -> Result<RomDatabase, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        RomDatabase::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<RomDatabase, String> {
+        let mut regions = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let crc = fields.next().unwrap();
+            let crc = u32::from_str_radix(crc, 16).map_err(|_| format!("line {}: invalid CRC32 '{}'", number + 1, crc))?;
+            let region = fields.next().ok_or(format!("line {}: missing region", number + 1))?;
+            let region = Region::parse(region).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            regions.insert(crc, region);
+        }
+        Ok(RomDatabase { regions })
+    }
+
+    pub fn region(&self, crc: u32) -> Option<Region> {
+        self.regions.get(&crc).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::test::test_rom;
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::Pal.frame_rate() - 50.007).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.007).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_region_from_rom_name() {
+        assert_eq!(Region::from_rom_name("Pac-Man (USA) (Tengen).nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_rom_name("Elite (Europe).nes"), Some(Region::Pal));
+        assert_eq!(Region::from_rom_name("Tetris (E) [!].nes"), Some(Region::Pal));
+        assert_eq!(Region::from_rom_name("homebrew.nes"), None);
+        assert_eq!(Region::from_rom_name("Kirby's Adventure (A) [!].nes"), Some(Region::Pal));
+        assert_eq!(Region::from_rom_name("Chip 'n Dale (g).nes"), Some(Region::Pal));
+        assert_eq!(Region::from_rom_name("Tetris (JU) [!].nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_rom_name("Bomberman (Japan, Europe).nes"), Some(Region::Ntsc));
+        assert_eq!(Region::from_rom_name("Mario (Prototype) (Europe, Australia).nes"), Some(Region::Pal));
+        // words in the title aren't tags
+        assert_eq!(Region::from_rom_name("Castlevania (Beta).nes"), None);
+        // and neither are other short tags, which aren't made of country codes
+        assert_eq!(Region::from_rom_name("Excitebike (VS).nes"), None);
+        assert_eq!(Region::from_rom_name("Super Mario Bros (As).nes"), None);
+        assert_eq!(Region::from_rom_name("Rad Racer (PC10).nes"), None);
+        assert_eq!(Region::from_rom_name("Duck Hunt (JUE) [!].nes"), Some(Region::Ntsc));
+    }
+
+    #[test]
+    fn test_region_from_database() {
+        // an iNES 1.0 header, no timing in it
+        let rom = test_rom();
+        assert_eq!(rom.region, None);
+        let database = RomDatabase::parse(&format!(
+            "# known dumps\n\n{:08x} pal Test Game (Europe)\n0000abcd dendy\n",
+            rom.crc32()
+        ))
+        .unwrap();
+        assert_eq!(Region::detect(&rom, &database, "test.nes"), Some(Region::Pal));
+        // the database knows better than the file name
+        assert_eq!(Region::detect(&rom, &database, "Test Game (USA).nes"), Some(Region::Pal));
+        assert_eq!(database.region(0xabcd), Some(Region::Dendy));
+        assert_eq!(Region::detect(&rom, &RomDatabase::default(), "test.nes"), None);
+
+        assert!(RomDatabase::parse("0123abcd secam").is_err());
+        assert!(RomDatabase::parse("xyz pal").is_err());
+    }
+}
@@ -1,15 +1,49 @@
 use crate::cpu::CPU;
-use crate::cpu::Mem;
 use crate::cpu::AddressingMode;
 use crate::opcodes;
-use crate::cart::test::test_rom;
 use std::collections::HashMap;
-use crate::ppu::MyPPU;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
-pub fn trace(cpu: &mut CPU) -> String {
+fn peek_u16(cpu: &CPU, addr: u16) -> u16 {
+    let lo = cpu.bus.peek(addr) as u16;
+    let hi = cpu.bus.peek(addr.wrapping_add(1)) as u16;
+    (hi << 8) | lo
+}
+
+// Same as CPU::get_stored_value_address, but without touching the bus
+fn effective_address(cpu: &CPU, mode: &AddressingMode, addr: u16) -> u16 {
+    match mode {
+        AddressingMode::ZeroPage => cpu.bus.peek(addr) as u16,
+        AddressingMode::Absolute => peek_u16(cpu, addr),
+        AddressingMode::ZeroPage_X => cpu.bus.peek(addr).wrapping_add(cpu.register_x) as u16,
+        AddressingMode::ZeroPage_Y => cpu.bus.peek(addr).wrapping_add(cpu.register_y) as u16,
+        AddressingMode::Absolute_X => peek_u16(cpu, addr).wrapping_add(cpu.register_x as u16),
+        AddressingMode::Absolute_Y => peek_u16(cpu, addr).wrapping_add(cpu.register_y as u16),
+        AddressingMode::Indirect_X => {
+            let ptr = cpu.bus.peek(addr).wrapping_add(cpu.register_x);
+            let lo = cpu.bus.peek(ptr as u16);
+            let hi = cpu.bus.peek(ptr.wrapping_add(1) as u16);
+            (hi as u16) << 8 | (lo as u16)
+        }
+        AddressingMode::Indirect_Y => {
+            let base = cpu.bus.peek(addr);
+            let lo = cpu.bus.peek(base as u16);
+            let hi = cpu.bus.peek(base.wrapping_add(1) as u16);
+            ((hi as u16) << 8 | (lo as u16)).wrapping_add(cpu.register_y as u16)
+        }
+        AddressingMode::Immediate | AddressingMode::NoneAddressing => addr,
+    }
+}
+
+// Formats the instruction at PC in the nestest.log style. Memory is only
+// peeked at, so tracing never disturbs PPU or controller state.
+pub fn trace(cpu: &CPU) -> String {
     let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
-    let code = cpu.mem_read(cpu.program_counter);
+    let code = cpu.bus.peek(cpu.program_counter);
     let ops = opcodes.get(&code).unwrap();
 
     let origin = cpu.program_counter;
@@ -22,8 +56,8 @@ pub fn trace(cpu: &mut CPU) -> String {
         }
 
         _ => {
-            let (addr, _) = cpu.get_stored_value_address(&ops.mode, origin + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = effective_address(cpu, &ops.mode, origin + 1);
+            (addr, cpu.bus.peek(addr))
         }
     };
 
@@ -33,8 +67,7 @@ pub fn trace(cpu: &mut CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(origin + 1);
-            // let value = cpu.mem_read(address));
+            let address: u8 = cpu.bus.peek(origin + 1);
             hex_dump.push(address);
 
             match ops.mode {
@@ -76,26 +109,25 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(origin + 1);
-            let address_hi = cpu.mem_read(origin + 2);
+            let address_lo = cpu.bus.peek(origin + 1);
+            let address_hi = cpu.bus.peek(origin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(origin + 1);
+            let address = peek_u16(cpu, origin + 1);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.bus.peek(address);
+                            let hi = cpu.bus.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            peek_u16(cpu, address)
                         };
 
-                        // let jmp_addr = cpu.mem_read_u16(address);
                         format!("(${:04x}) = {:04x}", address, jmp_addr)
                     } else {
                         format!("${:04x}", address)
@@ -135,15 +167,260 @@ pub fn trace(cpu: &mut CPU) -> String {
     .to_ascii_uppercase()
 }
 
+// Side-effect free disassembly of the instruction at `addr`. Returns the
+// formatted line and the instruction length.
+pub fn disassemble(cpu: &CPU, addr: u16) -> (String, u16) {
+    let code = cpu.bus.peek(addr);
+    let op = match opcodes::OPCODES_MAP.get(&code) {
+        Some(op) => op,
+        None => return (format!("{:04X}  {:02X}        ???", addr, code), 1),
+    };
+
+    let bytes: Vec<u8> = (0..op.len as u16).map(|i| cpu.bus.peek(addr.wrapping_add(i))).collect();
+    let lo = bytes.get(1).copied().unwrap_or(0);
+    let word = (bytes.get(2).copied().unwrap_or(0) as u16) << 8 | lo as u16;
+
+    let operand = match (&op.mode, op.len) {
+        (AddressingMode::Immediate, _) => format!("#${:02X}", lo),
+        (AddressingMode::ZeroPage, _) => format!("${:02X}", lo),
+        (AddressingMode::ZeroPage_X, _) => format!("${:02X},X", lo),
+        (AddressingMode::ZeroPage_Y, _) => format!("${:02X},Y", lo),
+        (AddressingMode::Absolute, _) => format!("${:04X}", word),
+        (AddressingMode::Absolute_X, _) => format!("${:04X},X", word),
+        (AddressingMode::Absolute_Y, _) => format!("${:04X},Y", word),
+        (AddressingMode::Indirect_X, _) => format!("(${:02X},X)", lo),
+        (AddressingMode::Indirect_Y, _) => format!("(${:02X}),Y", lo),
+        (AddressingMode::NoneAddressing, 2) => {
+            // relative branches
+            let target = addr.wrapping_add(2).wrapping_add((lo as i8) as u16);
+            format!("${:04X}", target)
+        }
+        (AddressingMode::NoneAddressing, 3) if code == 0x6c => format!("(${:04X})", word),
+        (AddressingMode::NoneAddressing, 3) => format!("${:04X}", word),
+        (AddressingMode::NoneAddressing, _) => match code {
+            0x0a | 0x4a | 0x2a | 0x6a => "A".to_string(),
+            _ => String::new(),
+        },
+    };
+
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    (
+        format!("{:04X}  {:8}  {} {}", addr, hex.join(" "), op.mnemonic, operand)
+            .trim_end()
+            .to_string(),
+        op.len as u16,
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // nestest.log style, as produced by `trace`
+    Nestest,
+    // Mesen's trace logger layout, for diffing against its logs
+    Mesen,
+}
+
+enum TraceSink {
+    File(BufWriter<File>),
+    Ring(VecDeque<String>, usize),
+}
+
+enum Trigger {
+    Always,
+    Address(u16),
+    Breakpoint(usize),
+}
+
+// Collects `trace` lines either straight into a file or into a bounded
+// in-memory ring buffer that can be written out on demand (e.g. after a
+// crash), with optional filtering on what gets logged.
+pub struct TraceLogger {
+    sink: TraceSink,
+    enabled: bool,
+    pub format: TraceFormat,
+    pub pc_range: Option<(u16, u16)>,
+    pub bank: Option<u8>,
+    pub show_ppu: bool,
+    pub show_cycles: bool,
+    trigger: Trigger,
+}
+
+impl TraceLogger {
+    fn new(sink: TraceSink) -> Self {
+        TraceLogger {
+            sink,
+            enabled: true,
+            format: TraceFormat::Nestest,
+            pc_range: None,
+            bank: None,
+            show_ppu: false,
+            show_cycles: false,
+            trigger: Trigger::Always,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(TraceLogger::new(TraceSink::File(BufWriter::new(file))))
+    }
+
+    pub fn ring_buffer(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        TraceLogger::new(TraceSink::Ring(VecDeque::with_capacity(capacity), capacity))
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Nothing is logged until the PC reaches `addr`
+    pub fn start_at_address(&mut self, addr: u16) {
+        self.trigger = Trigger::Address(addr);
+    }
+
+    // Nothing is logged until the debugger reports breakpoint `id`
+    pub fn start_at_breakpoint(&mut self, id: usize) {
+        self.trigger = Trigger::Breakpoint(id);
+    }
+
+    pub fn breakpoint_hit(&mut self, id: usize) {
+        if let Trigger::Breakpoint(wanted) = self.trigger {
+            if wanted == id {
+                self.trigger = Trigger::Always;
+            }
+        }
+    }
+
+    // Call once per instruction, before it executes
+    pub fn log(&mut self, cpu: &CPU) {
+        if !self.enabled {
+            return;
+        }
+        let pc = cpu.program_counter;
+        match self.trigger {
+            Trigger::Always => {}
+            Trigger::Address(addr) if addr == pc => self.trigger = Trigger::Always,
+            _ => return,
+        }
+        if let Some((start, end)) = self.pc_range {
+            if pc < start || pc > end {
+                return;
+            }
+        }
+        if let Some(bank) = self.bank {
+            if cpu.bus.prg_bank(pc) != Some(bank) {
+                return;
+            }
+        }
+
+        let line = self.format_line(cpu);
+        match &mut self.sink {
+            TraceSink::File(writer) => {
+                // a failing log file must not take the emulator down
+                if writeln!(writer, "{}", line).is_err() {
+                    self.enabled = false;
+                }
+            }
+            TraceSink::Ring(lines, capacity) => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        }
+    }
+
+    fn format_line(&self, cpu: &CPU) -> String {
+        let ppu = cpu.bus.ppu();
+        let mut line = match self.format {
+            TraceFormat::Nestest => trace(cpu),
+            TraceFormat::Mesen => {
+                let flags: String = "NVUBDIZC"
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| {
+                        if cpu.status.bits() & (0x80 >> i) != 0 {
+                            c
+                        } else {
+                            c.to_ascii_lowercase()
+                        }
+                    })
+                    .collect();
+                let text = disassemble(cpu, cpu.program_counter).0;
+                format!(
+                    "{:<48} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}",
+                    text, cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer, flags
+                )
+            }
+        };
+
+        match self.format {
+            TraceFormat::Nestest => {
+                if self.show_ppu {
+                    line.push_str(&format!(" PPU:{:3},{:3}", ppu.scanline(), ppu.cycle()));
+                }
+                if self.show_cycles {
+                    line.push_str(&format!(" CYC:{}", cpu.bus.cycles()));
+                }
+            }
+            TraceFormat::Mesen => {
+                if self.show_ppu {
+                    line.push_str(&format!(" V:{:<3} H:{:<3}", ppu.scanline(), ppu.cycle()));
+                }
+                if self.show_cycles {
+                    line.push_str(&format!(" Cycle:{}", cpu.bus.cycles()));
+                }
+            }
+        }
+        line
+    }
+
+    // Lines currently held by a ring buffer logger, oldest first
+    pub fn lines(&self) -> Vec<String> {
+        match &self.sink {
+            TraceSink::Ring(lines, _) => lines.iter().cloned().collect(),
+            TraceSink::File(_) => Vec::new(),
+        }
+    }
+
+    // Writes out the ring buffer, or flushes the file being logged to
+    pub fn dump<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        match &mut self.sink {
+            TraceSink::Ring(lines, _) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                for line in lines.iter() {
+                    writeln!(writer, "{}", line)?;
+                }
+                writer.flush()
+            }
+            TraceSink::File(writer) => writer.flush(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            TraceSink::File(writer) => writer.flush(),
+            TraceSink::Ring(_, _) => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bus::Bus;
     use crate::cart::test::test_rom;
+    use crate::controller::Joypad;
+    use crate::cpu::Mem;
+    use crate::ppu::MyPPU;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom(), |ppu: &MyPPU| {});
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -156,9 +433,9 @@ mod test {
         cpu.register_x = 2;
         cpu.register_y = 3;
         let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        for _ in 0..3 {
+            cpu.step_with_callback(|cpu| result.push(trace(cpu)));
+        }
         assert_eq!(
             "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
             result[0]
@@ -175,7 +452,7 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -191,12 +468,53 @@ mod test {
         cpu.program_counter = 0x64;
         cpu.register_y = 0;
         let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        cpu.step_with_callback(|cpu| result.push(trace(cpu)));
         assert_eq!(
             "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
             result[0]
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_ring_buffer_with_filters() {
+        // INX; INX; INX; JMP $0600
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        for (i, byte) in [0xe8, 0xe8, 0xe8, 0x4c, 0x00, 0x06].iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut logger = TraceLogger::ring_buffer(3);
+        logger.pc_range = Some((0x0601, 0x0602));
+        logger.start_at_breakpoint(1);
+        for _ in 0..4 {
+            cpu.step_with_callback(|cpu| logger.log(cpu));
+        }
+        assert!(logger.lines().is_empty());
+
+        logger.breakpoint_hit(1);
+        for _ in 0..12 {
+            cpu.step_with_callback(|cpu| logger.log(cpu));
+        }
+        let lines = logger.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("0602"));
+        assert!(lines[1].starts_with("0601"));
+        assert!(lines[2].starts_with("0602"));
+    }
+
+    #[test]
+    fn test_mesen_format() {
+        let mut bus = Bus::new(test_rom(), |_: &MyPPU, _: &mut Joypad| {});
+        bus.mem_write(0x0600, 0xe8);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0600;
+
+        let mut logger = TraceLogger::ring_buffer(1);
+        logger.format = TraceFormat::Mesen;
+        logger.log(&cpu);
+        let line = &logger.lines()[0];
+        assert!(line.ends_with("A:00 X:00 Y:00 S:FD P:nvUbdIzc"), "{}", line);
+    }
+}
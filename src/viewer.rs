@@ -0,0 +1,330 @@
+// PPU debug windows shown next to the game, and the same views dumped as
+// PNGs for runs without a display. The drawing is in graphics_data::render.
+//
+// PpuViewer: both pattern tables at twice their size on top, the four
+// nametables underneath. Tab cycles the palette the pattern tables are
+// drawn with.
+//
+// SpriteViewer: the last frame with all sprites outlined next to a grid of
+// all 64 OAM entries, and the palette RAM underneath. Sprites dropped by the
+// 8 per line limit are marked red, the one under the mouse yellow.
+//
+// EventViewer: the PPU register, OAM DMA and mapper writes of the last
+// frame on a dot by scanline grid. Clicking a marker shows its value.
+//
+// The first two show what's under the mouse in the title bar.
+//
+// AudioViewer: an oscilloscope trace per APU channel next to its register
+// state. It needs the APU, so unlike the others it's updated from the main
+// loop rather than the gameloop callback.
+
+use crate::apu::Apu;
+use crate::graphics_data::image::Image;
+use crate::graphics_data::render;
+use crate::graphics_data::scope::{self, Scope};
+use crate::ppu::{MyPPU, PpuEvent};
+use crate::region::Region;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+
+const PATTERN_SCALE: usize = 2;
+const SCREEN_SCALE: usize = 2;
+const OAM_X: usize = 256 * SCREEN_SCALE;
+const PALETTE_Y: usize = 240 * SCREEN_SCALE;
+const SPRITES_WIDTH: usize = OAM_X + render::OAM_CELL_WIDTH * 8;
+const SPRITES_HEIGHT: usize = PALETTE_Y + render::PALETTE_SWATCH * 2;
+const EVENTS_SCALE: usize = 2;
+const NAMETABLES_Y: usize = render::PATTERN_TABLES_HEIGHT * PATTERN_SCALE;
+const WIDTH: usize = render::NAMETABLES_WIDTH;
+const HEIGHT: usize = NAMETABLES_Y + render::NAMETABLES_HEIGHT;
+
+pub struct PpuViewer {
+    canvas: Canvas<Window>,
+    // palette the pattern tables are drawn with, 0-3 background, 4-7 sprites
+    pub palette: u8,
+    hover: Option<(usize, usize)>,
+}
+
+// The whole viewer as one picture
+pub fn draw(ppu: &MyPPU, palette: u8) -> Image {
+    let mut image = Image::new(WIDTH, HEIGHT);
+    image.blit(&render::pattern_tables(ppu, palette), 0, 0, PATTERN_SCALE);
+    image.blit(&render::nametables(ppu), 0, NAMETABLES_Y, 1);
+    image
+}
+
+// What's under (x, y) of the viewer
+pub fn describe(ppu: &MyPPU, x: usize, y: usize) -> String {
+    if y < NAMETABLES_Y {
+        render::pattern_tile_info(x / PATTERN_SCALE, y / PATTERN_SCALE)
+    } else {
+        render::nametable_tile_info(ppu, x, y - NAMETABLES_Y)
+    }
+}
+
+fn open_window(video: &VideoSubsystem, title: &str, width: usize, height: usize) -> Result<Canvas<Window>, String> {
+    let window = video
+        .window(title, width as u32, height as u32)
+        .build()
+        .map_err(|e| e.to_string())?;
+    window.into_canvas().build().map_err(|e| e.to_string())
+}
+
+fn present(canvas: &mut Canvas<Window>, image: &Image, title: &str) -> Result<(), String> {
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, image.width as u32, image.height as u32)
+        .map_err(|e| e.to_string())?;
+    texture.update(None, &image.data, image.width * 3).map_err(|e| e.to_string())?;
+    canvas.copy(&texture, None, None)?;
+    canvas.present();
+    canvas.window_mut().set_title(title).map_err(|e| e.to_string())
+}
+
+// Mouse position inside the window `window_id`, if the event is a move there
+fn mouse_motion(event: &Event, window_id: u32, width: usize, height: usize) -> Option<Option<(usize, usize)>> {
+    match *event {
+        Event::MouseMotion { window_id: id, x, y, .. } if id == window_id => {
+            if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+                Some(Some((x as usize, y as usize)))
+            } else {
+                Some(None)
+            }
+        }
+        _ => None,
+    }
+}
+
+impl PpuViewer {
+    pub fn new(video: &VideoSubsystem) -> Result<Self, String> {
+        Ok(PpuViewer {
+            canvas: open_window(video, "PPU viewer", WIDTH, HEIGHT)?,
+            palette: 0,
+            hover: None,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn update(&mut self, ppu: &MyPPU) -> Result<(), String> {
+        let title = match self.hover {
+            Some((x, y)) => describe(ppu, x, y),
+            None => format!("PPU viewer - palette {}", self.palette),
+        };
+        present(&mut self.canvas, &draw(ppu, self.palette), &title)
+    }
+
+    // Returns true if the event was meant for this window only
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        if let Some(hover) = mouse_motion(event, self.window_id(), WIDTH, HEIGHT) {
+            self.hover = hover;
+            return true;
+        }
+        match *event {
+            Event::KeyDown { window_id, keycode: Some(Keycode::Tab), .. } if window_id == self.window_id() => {
+                self.palette = (self.palette + 1) % 8;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct SpriteViewer {
+    canvas: Canvas<Window>,
+    hover: Option<(usize, usize)>,
+}
+
+enum SpriteHover {
+    Sprite(usize),
+    Palette(usize),
+    Nothing,
+}
+
+fn sprite_hover(ppu: &MyPPU, x: usize, y: usize) -> SpriteHover {
+    if y >= PALETTE_Y {
+        let entry = (y - PALETTE_Y) / render::PALETTE_SWATCH * 16 + x / render::PALETTE_SWATCH;
+        return if entry < 32 { SpriteHover::Palette(entry) } else { SpriteHover::Nothing };
+    }
+    let sprite = if x >= OAM_X {
+        render::oam_cell_at(x - OAM_X, y)
+    } else {
+        render::sprite_at(ppu, x / SCREEN_SCALE, y / SCREEN_SCALE)
+    };
+    sprite.map_or(SpriteHover::Nothing, SpriteHover::Sprite)
+}
+
+// The whole sprite viewer as one picture, `selected` highlighted
+pub fn draw_sprites(ppu: &MyPPU, selected: Option<usize>) -> Image {
+    let mut image = Image::new(SPRITES_WIDTH, SPRITES_HEIGHT);
+    image.blit(&render::sprite_overlay(ppu, selected), 0, 0, SCREEN_SCALE);
+    image.blit(&render::oam_grid(ppu, selected), OAM_X, 0, 1);
+    image.blit(&render::palette_view(ppu), 0, PALETTE_Y, 1);
+    image
+}
+
+impl SpriteViewer {
+    pub fn new(video: &VideoSubsystem) -> Result<Self, String> {
+        Ok(SpriteViewer {
+            canvas: open_window(video, "Sprite viewer", SPRITES_WIDTH, SPRITES_HEIGHT)?,
+            hover: None,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn update(&mut self, ppu: &MyPPU) -> Result<(), String> {
+        let hover = self.hover.map_or(SpriteHover::Nothing, |(x, y)| sprite_hover(ppu, x, y));
+        let (selected, title) = match hover {
+            SpriteHover::Sprite(index) => (Some(index), render::sprites(ppu)[index].to_string()),
+            SpriteHover::Palette(entry) => (None, render::palette_entry_info(ppu, entry)),
+            SpriteHover::Nothing => {
+                let dropped = ppu.dropped_sprites().count_ones();
+                (None, format!("Sprite viewer - {} dropped", dropped))
+            }
+        };
+        present(&mut self.canvas, &draw_sprites(ppu, selected), &title)
+    }
+
+    // Returns true if the event was meant for this window only
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match mouse_motion(event, self.window_id(), SPRITES_WIDTH, SPRITES_HEIGHT) {
+            Some(hover) => {
+                self.hover = hover;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct EventViewer {
+    canvas: Canvas<Window>,
+    selected: Option<PpuEvent>,
+}
+
+pub fn draw_events(ppu: &MyPPU, selected: Option<PpuEvent>) -> Image {
+    let grid = render::event_grid(ppu);
+    let mut image = Image::new(grid.width * EVENTS_SCALE, grid.height * EVENTS_SCALE);
+    image.blit(&grid, 0, 0, EVENTS_SCALE);
+    if let Some(event) = selected {
+        let x = event.dot.saturating_sub(2) * EVENTS_SCALE;
+        let y = (event.scanline as usize).saturating_sub(2) * EVENTS_SCALE;
+        image.draw_rect(x, y, 5 * EVENTS_SCALE, 5 * EVENTS_SCALE, (0xff, 0xff, 0xff));
+    }
+    image
+}
+
+impl EventViewer {
+    pub fn new(video: &VideoSubsystem, region: Region) -> Result<Self, String> {
+        let height = region.scanlines() as usize * EVENTS_SCALE;
+        Ok(EventViewer {
+            canvas: open_window(video, "Event viewer", render::DOTS_PER_LINE * EVENTS_SCALE, height)?,
+            selected: None,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn update(&mut self, ppu: &MyPPU) -> Result<(), String> {
+        let title = match self.selected.as_ref() {
+            Some(event) => render::describe_event(event),
+            None => format!("Event viewer - {} events", ppu.events().len()),
+        };
+        present(&mut self.canvas, &draw_events(ppu, self.selected), &title)
+    }
+
+    // Clicks pick the event to inspect. The PPU is needed to find it, so
+    // this takes the position and the caller looks the event up.
+    pub fn handle_event(&mut self, event: &Event) -> Option<(usize, usize)> {
+        match *event {
+            Event::MouseButtonDown { window_id, x, y, .. } if window_id == self.window_id() => {
+                Some((x.max(0) as usize / EVENTS_SCALE, y.max(0) as usize / EVENTS_SCALE))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn select(&mut self, ppu: &MyPPU, dot: usize, scanline: usize) {
+        self.selected = render::event_at(ppu, dot, scanline);
+        if let Some(event) = self.selected.as_ref() {
+            println!("{}", render::describe_event(event));
+        }
+    }
+}
+
+pub struct AudioViewer {
+    canvas: Canvas<Window>,
+    scope: Scope,
+    closed: bool,
+}
+
+impl AudioViewer {
+    pub fn new(video: &VideoSubsystem) -> Result<Self, String> {
+        Ok(AudioViewer {
+            canvas: open_window(video, "Audio viewer", scope::WIDTH, scope::HEIGHT)?,
+            scope: Scope::new(),
+            closed: false,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    // Starts taking the channel outputs from `apu`
+    pub fn attach(&self, apu: &mut Apu) {
+        self.scope.attach(apu);
+    }
+
+    // The window goes away on the next update, once the APU is at hand
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn detach(&self, apu: &mut Apu) {
+        self.scope.detach(apu);
+    }
+
+    pub fn update(&mut self, apu: &Apu) -> Result<(), String> {
+        self.scope.update();
+        present(&mut self.canvas, &self.scope.draw(apu), "Audio viewer")
+    }
+}
+
+// Writes the current picture and the debug views to `dir`
+pub fn export_pngs(ppu: &MyPPU, dir: &str) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    let path = |name: &str| format!("{}/{}", dir, name);
+
+    let frame = Image {
+        width: 256,
+        height: 240,
+        data: ppu.frame.data.clone(),
+    };
+    frame.save_png(&path("frame.png"))?;
+    render::pattern_tables(ppu, 0).save_png(&path("pattern_tables.png"))?;
+    render::nametables(ppu).save_png(&path("nametables.png"))?;
+    draw_sprites(ppu, None).save_png(&path("sprites.png"))?;
+    draw_events(ppu, None).save_png(&path("events.png"))?;
+    let events: Vec<String> = ppu.events().iter().map(render::describe_event).collect();
+    std::fs::write(path("events.txt"), events.join("\n") + "\n").map_err(|e| e.to_string())?;
+
+    let oam: Vec<String> = render::sprites(ppu).iter().map(|s| s.to_string()).collect();
+    std::fs::write(path("oam.txt"), oam.join("\n") + "\n").map_err(|e| e.to_string())
+}